@@ -0,0 +1,249 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::ops::Range;
+use std::path::Path;
+use thin_shunting::{Grammar, MathContext, MathToken, MathTokenizer, ShuntingParser};
+
+// An interactive calculator, variables and functions defined on one line
+// are there for the next and 'ans' holds the last result
+
+const PROMPT: &str = ">> ";
+
+const HELP: &str = "\
+Enter expressions like 'sin(pi / 4)^2', assignments like 'x = 2ans' or
+functions like 'f(x, y) = x^2 + y^2'. Commands:
+  :vars   list variables
+  :funcs  list functions
+  :clear  forget variables and functions
+  :help   show this help
+  :quit   leave, as does Ctrl-D";
+
+// an error and the part of the line it's about
+#[derive(PartialEq, Debug)]
+struct Error {
+    message: String,
+    span: Option<Range<usize>>,
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error {
+            message,
+            span: None,
+        }
+    }
+}
+
+struct Repl {
+    cx: MathContext,
+    grammar: Grammar,
+}
+
+impl Repl {
+    fn new() -> Repl {
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            ..Grammar::default()
+        };
+        Repl {
+            cx: MathContext::new(),
+            grammar,
+        }
+    }
+
+    // run a line, returns the lines to show for it
+    fn run(&mut self, line: &str) -> Result<Vec<String>, Error> {
+        match line.trim() {
+            "" => Ok(Vec::new()),
+            ":vars" => {
+                let mut vars: Vec<_> = self.cx.vars.iter().collect();
+                vars.sort_by(|a, b| a.0.cmp(b.0));
+                Ok(vars
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", name, value))
+                    .collect())
+            }
+            ":funcs" => {
+                let mut funcs: Vec<_> = self.cx.funcs.values().collect();
+                funcs.sort_by(|a, b| a.name.cmp(&b.name));
+                let funcs = funcs.iter();
+                let defs = funcs
+                    .map(|def| format!("{}({}) = {}", def.name, def.params.join(", "), def.body));
+                Ok(defs.collect())
+            }
+            ":clear" => {
+                self.cx = MathContext::new();
+                Ok(Vec::new())
+            }
+            ":help" => Ok(vec![HELP.to_string()]),
+            command if command.starts_with(':') => {
+                Err(format!("Unknown command: {}, see :help", command).into())
+            }
+            _ => self.eval(line),
+        }
+    }
+
+    fn eval(&mut self, line: &str) -> Result<Vec<String>, Error> {
+        let mut lex = MathTokenizer::with_grammar(line.chars(), self.grammar.clone());
+        let tokens: Vec<_> =
+            std::iter::from_fn(|| lex.next().map(|token| (token, lex.span()))).collect();
+        let is_equals = |token: &MathToken| *token == MathToken::BOp("=".to_string());
+        match tokens[..] {
+            [(MathToken::Variable(ref name), _), (ref equals, ref span), ..]
+                if is_equals(equals) =>
+            {
+                let value = self.eval_from(line, span.end)?;
+                self.cx.setvar(name, value);
+                self.cx.setvar("ans", value);
+                Ok(vec![format!("{} = {}", name, value)])
+            }
+            [(MathToken::Function(..), _), ..]
+                if tokens.iter().any(|(token, _)| is_equals(token)) =>
+            {
+                let def = ShuntingParser::parse_definition_with(line, &self.grammar)?;
+                self.cx.setfn(def)?;
+                Ok(Vec::new())
+            }
+            _ => {
+                let value = self.eval_from(line, 0)?;
+                self.cx.setvar("ans", value);
+                Ok(vec![value.to_string()])
+            }
+        }
+    }
+
+    // evaluate the expression starting 'start' chars into 'line'
+    fn eval_from(&self, line: &str, start: usize) -> Result<f64, Error> {
+        let shift = |span: Range<usize>| span.start + start..span.end + start;
+        let src: String = line.chars().skip(start).collect();
+        let spanned = match ShuntingParser::parse_str_located(&src, &self.grammar) {
+            Ok(spanned) => spanned,
+            Err((message, span)) => {
+                let span = Some(shift(span));
+                return Err(Error { message, span });
+            }
+        };
+        if spanned.expr.0.is_empty() {
+            return Err("Nothing to evaluate".to_string().into());
+        }
+        // point the error at the token that failed, eg: the 'z' in
+        // 'Unknown Variable: z' or the call that got too many arguments
+        self.cx
+            .eval_located(&spanned.expr)
+            .map_err(|(message, at)| Error {
+                span: at.map(|at| shift(spanned.spans[at].clone())),
+                message,
+            })
+    }
+}
+
+fn show_error(error: &Error) {
+    if let Some(ref span) = error.span {
+        let indent = " ".repeat(PROMPT.len() + span.start);
+        eprintln!("{}{}", indent, "^".repeat(span.len().max(1)));
+    }
+    eprintln!("error: {}", error.message);
+}
+
+fn main() {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(".shunt_history"));
+    if let Some(ref history) = history {
+        // there's none on the first run
+        let _ = editor.load_history(history);
+    }
+    let mut repl = Repl::new();
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            // Ctrl-C drops the line being edited
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                break;
+            }
+        };
+        if line.trim() == ":quit" {
+            break;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        match repl.run(&line) {
+            Ok(output) => output.iter().for_each(|line| println!("{}", line)),
+            Err(error) => show_error(&error),
+        }
+    }
+    if let Some(history) = history {
+        if let Err(error) = editor.save_history(&history) {
+            eprintln!("error: can't save history: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(message: &str, span: Option<Range<usize>>) -> Result<Vec<String>, Error> {
+        let message = message.to_string();
+        Err(Error { message, span })
+    }
+
+    #[test]
+    fn session() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.run("1 + 2"), Ok(vec!["3".to_string()]));
+        assert_eq!(repl.run("x = 2ans"), Ok(vec!["x = 6".to_string()]));
+        assert_eq!(repl.run("f(t) = t^2 + x"), Ok(Vec::new()));
+        assert_eq!(repl.run("f(ans) "), Ok(vec!["42".to_string()]));
+        assert_eq!(repl.run("  "), Ok(Vec::new()));
+        assert_eq!(repl.run(":funcs"), Ok(vec!["f(t) = t ^ 2 + x".to_string()]));
+        let vars = repl.run(":vars").unwrap();
+        assert_eq!(vars[0], "ans = 42");
+        assert_eq!(vars[3], "x = 6");
+        assert_eq!(repl.run(":clear"), Ok(Vec::new()));
+        assert_eq!(repl.run(":vars").unwrap().len(), 2);
+        assert_eq!(repl.run(":funcs"), Ok(Vec::new()));
+        assert!(repl.run(":help").unwrap()[0].contains(":vars"));
+        assert_eq!(
+            repl.run(":nope"),
+            error("Unknown command: :nope, see :help", None)
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            repl.run("1 + (2 * 3"),
+            error("Missing Closing Paren", Some(4..5))
+        );
+        assert_eq!(
+            repl.run("y = 2 * z"),
+            error("Unknown Variable: z", Some(8..9))
+        );
+        repl.run("f(t) = t + 1").unwrap();
+        assert_eq!(
+            repl.run("1 + f(1, 2)"),
+            error("Wrong number of arguments to f", Some(4..11))
+        );
+        // the call that failed, not the first one to f
+        assert_eq!(
+            repl.run("f(1) + f(1, 2)"),
+            error("Wrong number of arguments to f", Some(7..14))
+        );
+        assert_eq!(repl.run("x ="), error("Nothing to evaluate", None));
+        assert_eq!(
+            repl.run("f(x, x) = x"),
+            error("Duplicate parameter: x", None)
+        );
+        // the failed assignment left nothing behind
+        assert_eq!(repl.run("y"), error("Unknown Variable: y", Some(0..1)));
+    }
+}
@@ -0,0 +1,986 @@
+use crate::grammar::Fixity;
+use crate::parser::{binds_variable, RPNExpr};
+use crate::rpnprint::AST;
+use crate::tokenizer::MathToken;
+
+// Precedence of generated code, C-like languages mostly agree on these
+const COND: u8 = 10; // ternaries and if expressions
+const AND: u8 = 20;
+const EQ: u8 = 40;
+const CMP: u8 = 50;
+const ADD: u8 = 60;
+const MUL: u8 = 70;
+const UNARY: u8 = 80;
+const POW: u8 = 90; // for languages with a power operator
+const ATOM: u8 = 100; // literals, names, calls and anything in parens
+
+// A piece of generated code, 'boolean' is for comparisons in languages
+// where they don't yield numbers
+pub(crate) struct Code {
+    text: String,
+    prec: u8,
+    boolean: bool,
+}
+
+impl Code {
+    pub(crate) fn new(text: String, prec: u8) -> Self {
+        Code {
+            text,
+            prec,
+            boolean: false,
+        }
+    }
+
+    pub(crate) fn boolean(text: String, prec: u8) -> Self {
+        Code {
+            text,
+            prec,
+            boolean: true,
+        }
+    }
+
+    // the text, in parens if it binds looser than 'prec'
+    pub(crate) fn at(&self, prec: u8) -> String {
+        if self.prec < prec {
+            format!("({})", self.text)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+// 'lhs op rhs' for a left associative operator
+pub(crate) fn infix(lhs: &Code, op: &str, rhs: &Code, prec: u8) -> Code {
+    Code::new(
+        format!("{} {} {}", lhs.at(prec), op, rhs.at(prec + 1)),
+        prec,
+    )
+}
+
+// '-x', with parens around a negative operand so '-(-x)' doesn't become
+// '--x', a decrement in C
+pub(crate) fn negate(operand: &Code) -> Code {
+    Code::new(format!("-{}", operand.at(UNARY + 1)), UNARY)
+}
+
+pub(crate) fn call(name: &str, args: &[Code]) -> Code {
+    let args: Vec<_> = args.iter().map(|arg| arg.text.clone()).collect();
+    Code::new(format!("{}({})", name, args.join(", ")), ATOM)
+}
+
+// fold a variadic call like max(a, b, c) into max(max(a, b), c)
+pub(crate) fn fold(name: &str, args: Vec<Code>) -> Code {
+    let mut args = args.into_iter();
+    let first = args.next().unwrap();
+    args.fold(first, |acc, arg| call(name, &[acc, arg]))
+}
+
+pub(crate) fn unsupported_fn(name: &str, target: &str) -> String {
+    format!("Unsupported function in {}: {}", target, name)
+}
+
+pub(crate) fn unsupported_op(op: &str, target: &str) -> String {
+    format!("Unsupported operator in {}: {}", target, op)
+}
+
+// shortest float literal that reads back the same, eg: 2.0 or 1e-7
+pub(crate) fn float(x: f64) -> Code {
+    let prec = if x.is_sign_negative() { UNARY } else { ATOM };
+    Code::new(format!("{:?}", x), prec)
+}
+
+// How a language writes each piece, the tree walk is shared
+pub(crate) trait Target {
+    fn name(&self) -> &str;
+    fn number(&self, x: f64) -> Result<Code, String>;
+    // a variable that isn't a parameter, like pi
+    fn constant(&self, name: &str) -> Option<Code>;
+    fn function(&self, name: &str, args: Vec<Code>) -> Result<Code, String>;
+    fn binary(&self, op: &str, lhs: Code, rhs: Code) -> Result<Code, String>;
+    fn unary(&self, op: &str, fixity: Fixity, operand: Code) -> Result<Code, String>;
+    // comparisons as numbers and numbers as conditions
+    fn to_number(&self, code: Code) -> Code;
+    fn to_bool(&self, code: Code) -> Code;
+    // evaluate only one of the branches
+    fn select(&self, cond: Code, then: Code, other: Code) -> Code;
+
+    fn parameter(&self, name: &str) -> Code {
+        Code::new(name.to_string(), ATOM)
+    }
+
+    fn compare(&self, op: &str, lhs: Code, rhs: Code, prec: u8) -> Code {
+        Code::boolean(infix(&lhs, op, &rhs, prec).text, prec)
+    }
+
+    fn and(&self, lhs: Code, rhs: Code) -> Code {
+        Code::boolean(infix(&lhs, "&&", &rhs, AND).text, AND)
+    }
+
+    // the walk leaves conversions to the operators that need them
+    fn numeric(&self, code: Code) -> Code {
+        if code.boolean {
+            self.to_number(code)
+        } else {
+            code
+        }
+    }
+
+    fn condition(&self, code: Code) -> Code {
+        if code.boolean {
+            code
+        } else {
+            self.to_bool(code)
+        }
+    }
+}
+
+fn binary<T: Target>(target: &T, op: &str, lhs: Code, rhs: Code) -> Result<Code, String> {
+    let prec = match op {
+        "<" | "<=" | ">" | ">=" => CMP,
+        "==" | "!=" => EQ,
+        "&&" => {
+            let (lhs, rhs) = (target.condition(lhs), target.condition(rhs));
+            return Ok(target.and(lhs, rhs));
+        }
+        _ => return target.binary(op, target.numeric(lhs), target.numeric(rhs)),
+    };
+    let (lhs, rhs) = (target.numeric(lhs), target.numeric(rhs));
+    Ok(target.compare(op, lhs, rhs, prec))
+}
+
+pub(crate) fn generate<T: Target>(target: &T, ast: &AST, vars: &[&str]) -> Result<Code, String> {
+    let (token, asts) = match *ast {
+        AST::Leaf(MathToken::Number(x, _)) => return target.number(*x),
+        AST::Leaf(MathToken::Integer(n, _)) => return target.number(*n as f64),
+        AST::Leaf(MathToken::Variable(var)) => {
+            return match target.constant(var) {
+                _ if vars.contains(&&var[..]) => Ok(target.parameter(var)),
+                Some(constant) => Ok(constant),
+                None => Err(format!("Unknown variable: {}", var)),
+            };
+        }
+        AST::Leaf(MathToken::Unit(unit)) => {
+            return Err(format!(
+                "Unsupported unit in {}: {}",
+                target.name(),
+                unit.name
+            ))
+        }
+        AST::Leaf(_) => unreachable!(),
+        // these would need loops rather than an expression
+        AST::Node(MathToken::Function(ref name, _), _) if binds_variable(name) => {
+            return Err(unsupported_fn(name, target.name()))
+        }
+        AST::Node(token, ref asts) => (token, asts),
+    };
+    let mut args = Vec::new();
+    for arg in asts.iter() {
+        args.push(generate(target, arg, vars)?);
+    }
+    match *token {
+        MathToken::BOp(ref op) => {
+            let rhs = args.pop().unwrap();
+            let lhs = args.pop().unwrap();
+            binary(target, op, lhs, rhs)
+        }
+        MathToken::UOp(ref op, fixity) => {
+            let operand = target.numeric(args.pop().unwrap());
+            target.unary(op, fixity, operand)
+        }
+        MathToken::Function(ref name, _) if name == "if" || name == "piecewise" => {
+            if args.len() % 2 == 0 || (name == "if" && args.len() != 3) {
+                return Err(format!("Wrong number of arguments to {}", name));
+            }
+            // piecewise(c1, v1, c2, v2, default) nests like if(c1, v1, if(c2, ...))
+            let mut default = target.numeric(args.pop().unwrap());
+            while let (Some(value), Some(cond)) = (args.pop(), args.pop()) {
+                let cond = target.condition(cond);
+                default = target.select(cond, target.numeric(value), default);
+            }
+            Ok(default)
+        }
+        MathToken::Function(ref name, _) => {
+            let args = args.into_iter().map(|arg| target.numeric(arg)).collect();
+            target.function(name, args)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn parameters<'a>(expr: &'a RPNExpr, vars: &[&str]) -> Result<AST<'a>, String> {
+    for (i, var) in vars.iter().enumerate() {
+        if vars[..i].contains(var) {
+            return Err(format!("Duplicate parameter: {}", var));
+        }
+    }
+    expr.build_ast()
+}
+
+struct Rust;
+
+impl Target for Rust {
+    fn name(&self) -> &str {
+        "Rust"
+    }
+
+    fn number(&self, x: f64) -> Result<Code, String> {
+        Ok(match x {
+            _ if x.is_nan() => Code::new("f64::NAN".to_string(), ATOM),
+            _ if x.is_infinite() && x > 0.0 => Code::new("f64::INFINITY".to_string(), ATOM),
+            _ if x.is_infinite() => Code::new("f64::NEG_INFINITY".to_string(), ATOM),
+            _ => float(x),
+        })
+    }
+
+    fn constant(&self, name: &str) -> Option<Code> {
+        match name {
+            "pi" => Some(Code::new("std::f64::consts::PI".to_string(), ATOM)),
+            "e" => Some(Code::new("std::f64::consts::E".to_string(), ATOM)),
+            _ => None,
+        }
+    }
+
+    fn function(&self, name: &str, args: Vec<Code>) -> Result<Code, String> {
+        Ok(match (name, args.len()) {
+            ("sin", 1) | ("cos", 1) | ("abs", 1) | ("atan2", 2) => {
+                call(&format!("f64::{}", name), &args)
+            }
+            ("max", n) | ("min", n) if n > 0 => fold(&format!("f64::{}", name), args),
+            ("nMPr", 2) => call("f64::powf", &args),
+            _ => return Err(unsupported_fn(name, self.name())),
+        })
+    }
+
+    fn binary(&self, op: &str, lhs: Code, rhs: Code) -> Result<Code, String> {
+        Ok(match op {
+            "+" | "-" => infix(&lhs, op, &rhs, ADD),
+            "*" | "/" | "%" => infix(&lhs, op, &rhs, MUL),
+            "^" => call("f64::powf", &[lhs, rhs]),
+            _ => return Err(unsupported_op(op, self.name())),
+        })
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Code) -> Result<Code, String> {
+        match (op, fixity) {
+            ("-", Fixity::Prefix) => Ok(negate(&operand)),
+            _ => Err(unsupported_op(op, self.name())),
+        }
+    }
+
+    fn to_number(&self, code: Code) -> Code {
+        Code::new(format!("f64::from(u8::from({}))", code.text), ATOM)
+    }
+
+    fn to_bool(&self, code: Code) -> Code {
+        Code::boolean(format!("{} != 0.0", code.at(EQ + 1)), EQ)
+    }
+
+    fn select(&self, cond: Code, then: Code, other: Code) -> Code {
+        let text = format!(
+            "if {} {{ {} }} else {{ {} }}",
+            cond.text, then.text, other.text
+        );
+        Code::new(text, COND)
+    }
+}
+
+struct C {
+    // rand() needs <stdlib.h>
+    stdlib: std::cell::Cell<bool>,
+}
+
+impl Target for C {
+    fn name(&self) -> &str {
+        "C"
+    }
+
+    fn number(&self, x: f64) -> Result<Code, String> {
+        Ok(match x {
+            _ if x.is_nan() => Code::new("NAN".to_string(), ATOM),
+            _ if x.is_infinite() && x > 0.0 => Code::new("INFINITY".to_string(), ATOM),
+            _ if x.is_infinite() => Code::new("-INFINITY".to_string(), UNARY),
+            _ => float(x),
+        })
+    }
+
+    fn constant(&self, name: &str) -> Option<Code> {
+        match name {
+            "pi" => Some(Code::new("M_PI".to_string(), ATOM)),
+            "e" => Some(Code::new("M_E".to_string(), ATOM)),
+            _ => None,
+        }
+    }
+
+    fn function(&self, name: &str, args: Vec<Code>) -> Result<Code, String> {
+        Ok(match (name, args.len()) {
+            ("sin", 1) | ("cos", 1) | ("atan2", 2) => call(name, &args),
+            ("abs", 1) => call("fabs", &args),
+            ("max", n) if n > 0 => fold("fmax", args),
+            ("min", n) if n > 0 => fold("fmin", args),
+            ("nMPr", 2) => call("pow", &args),
+            ("rand", 1) => {
+                self.stdlib.set(true);
+                let random = Code::new("rand() / (RAND_MAX + 1.0)".to_string(), MUL);
+                infix(&args[0], "*", &random, MUL)
+            }
+            _ => return Err(unsupported_fn(name, self.name())),
+        })
+    }
+
+    fn binary(&self, op: &str, lhs: Code, rhs: Code) -> Result<Code, String> {
+        Ok(match op {
+            "+" | "-" => infix(&lhs, op, &rhs, ADD),
+            "*" | "/" => infix(&lhs, op, &rhs, MUL),
+            "%" => call("fmod", &[lhs, rhs]),
+            "^" => call("pow", &[lhs, rhs]),
+            _ => return Err(unsupported_op(op, self.name())),
+        })
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Code) -> Result<Code, String> {
+        match (op, fixity) {
+            ("-", Fixity::Prefix) => Ok(negate(&operand)),
+            ("!", Fixity::Postfix) => {
+                let one = Code::new("1.0".to_string(), ATOM);
+                Ok(call("tgamma", &[infix(&operand, "+", &one, ADD)]))
+            }
+            _ => Err(unsupported_op(op, self.name())),
+        }
+    }
+
+    // comparisons are ints in C, which promote to double on their own
+    fn to_number(&self, code: Code) -> Code {
+        Code::new(code.text, code.prec)
+    }
+
+    fn to_bool(&self, code: Code) -> Code {
+        Code::boolean(format!("{} != 0.0", code.at(EQ + 1)), EQ)
+    }
+
+    fn select(&self, cond: Code, then: Code, other: Code) -> Code {
+        let text = format!(
+            "{} ? {} : {}",
+            cond.at(COND + 1),
+            then.at(COND + 1),
+            other.at(COND)
+        );
+        Code::new(text, COND)
+    }
+}
+
+// GLSL and WGSL differ in little more than spelling
+struct Shader {
+    wgsl: bool,
+}
+
+impl Target for Shader {
+    fn name(&self) -> &str {
+        if self.wgsl {
+            "WGSL"
+        } else {
+            "GLSL"
+        }
+    }
+
+    fn number(&self, x: f64) -> Result<Code, String> {
+        if !x.is_finite() {
+            return Err(format!("Can't write {} in {}", x, self.name()));
+        }
+        Ok(float(x))
+    }
+
+    // there are no built-in constants in shaders
+    fn constant(&self, name: &str) -> Option<Code> {
+        match name {
+            "pi" => Some(float(std::f64::consts::PI)),
+            "e" => Some(float(std::f64::consts::E)),
+            _ => None,
+        }
+    }
+
+    fn function(&self, name: &str, args: Vec<Code>) -> Result<Code, String> {
+        Ok(match (name, args.len()) {
+            ("sin", 1) | ("cos", 1) | ("abs", 1) => call(name, &args),
+            ("atan2", 2) if self.wgsl => call("atan2", &args),
+            ("atan2", 2) => call("atan", &args),
+            ("max", n) | ("min", n) if n > 0 => fold(name, args),
+            ("nMPr", 2) => call("pow", &args),
+            _ => return Err(unsupported_fn(name, self.name())),
+        })
+    }
+
+    fn binary(&self, op: &str, lhs: Code, rhs: Code) -> Result<Code, String> {
+        Ok(match op {
+            "+" | "-" => infix(&lhs, op, &rhs, ADD),
+            "*" | "/" => infix(&lhs, op, &rhs, MUL),
+            "%" if self.wgsl => infix(&lhs, op, &rhs, MUL),
+            // GLSL's mod() floors, the remainder truncates like WGSL's %
+            "%" => {
+                let quotient = call("trunc", &[infix(&lhs, "/", &rhs, MUL)]);
+                infix(&lhs, "-", &infix(&rhs, "*", &quotient, MUL), ADD)
+            }
+            // pow() is undefined for negative bases, so squares and cubes
+            // are written out and anything else is only good for x >= 0
+            "^" if rhs.text == "2.0" => infix(&lhs, "*", &lhs, MUL),
+            "^" if rhs.text == "3.0" => infix(&infix(&lhs, "*", &lhs, MUL), "*", &lhs, MUL),
+            "^" => call("pow", &[lhs, rhs]),
+            _ => return Err(unsupported_op(op, self.name())),
+        })
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Code) -> Result<Code, String> {
+        match (op, fixity) {
+            ("-", Fixity::Prefix) => Ok(negate(&operand)),
+            _ => Err(unsupported_op(op, self.name())),
+        }
+    }
+
+    fn to_number(&self, code: Code) -> Code {
+        let float = if self.wgsl { "f32" } else { "float" };
+        call(float, &[code])
+    }
+
+    fn to_bool(&self, code: Code) -> Code {
+        Code::boolean(format!("{} != 0.0", code.at(EQ + 1)), EQ)
+    }
+
+    // WGSL has no ternary, select() evaluates both sides but they have no
+    // side effects
+    fn select(&self, cond: Code, then: Code, other: Code) -> Code {
+        if self.wgsl {
+            return call("select", &[other, then, cond]);
+        }
+        let text = format!(
+            "{} ? {} : {}",
+            cond.at(COND + 1),
+            then.at(COND + 1),
+            other.at(COND)
+        );
+        Code::new(text, COND)
+    }
+}
+
+struct NumPy;
+
+impl Target for NumPy {
+    fn name(&self) -> &str {
+        "NumPy"
+    }
+
+    fn number(&self, x: f64) -> Result<Code, String> {
+        Ok(match x {
+            _ if x.is_nan() => Code::new("np.nan".to_string(), ATOM),
+            _ if x.is_infinite() && x > 0.0 => Code::new("np.inf".to_string(), ATOM),
+            _ if x.is_infinite() => Code::new("-np.inf".to_string(), UNARY),
+            _ => float(x),
+        })
+    }
+
+    fn constant(&self, name: &str) -> Option<Code> {
+        match name {
+            "pi" => Some(Code::new("np.pi".to_string(), ATOM)),
+            "e" => Some(Code::new("np.e".to_string(), ATOM)),
+            _ => None,
+        }
+    }
+
+    fn function(&self, name: &str, mut args: Vec<Code>) -> Result<Code, String> {
+        Ok(match (name, args.len()) {
+            ("sin", 1) | ("cos", 1) | ("abs", 1) => call(&format!("np.{}", name), &args),
+            ("atan2", 2) => call("np.arctan2", &args),
+            ("max", n) | ("min", n) if n > 0 => {
+                let ufunc = if name == "max" { "maximum" } else { "minimum" };
+                // broadcast first, a list mixing arrays and scalars isn't
+                // an array numpy can reduce
+                let items: Vec<_> = args.into_iter().map(|arg| arg.text).collect();
+                let items = format!("np.broadcast_arrays({})", items.join(", "));
+                Code::new(format!("np.{}.reduce({})", ufunc, items), ATOM)
+            }
+            ("nMPr", 2) => {
+                let rhs = args.pop().unwrap();
+                self.binary("^", args.pop().unwrap(), rhs)?
+            }
+            ("rand", 1) => {
+                let random = call("np.random.random", &[call("np.shape", &args)]);
+                infix(&args[0], "*", &random, MUL)
+            }
+            _ => return Err(unsupported_fn(name, self.name())),
+        })
+    }
+
+    fn binary(&self, op: &str, lhs: Code, rhs: Code) -> Result<Code, String> {
+        Ok(match op {
+            "+" | "-" => infix(&lhs, op, &rhs, ADD),
+            "*" | "/" => infix(&lhs, op, &rhs, MUL),
+            // Python's % floors, fmod truncates like the evaluator
+            "%" => call("np.fmod", &[lhs, rhs]),
+            // right associative and binds tighter than a unary minus on its left
+            "^" => Code::new(format!("{} ** {}", lhs.at(POW + 1), rhs.at(UNARY)), POW),
+            _ => return Err(unsupported_op(op, self.name())),
+        })
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Code) -> Result<Code, String> {
+        match (op, fixity) {
+            ("-", Fixity::Prefix) => Ok(negate(&operand)),
+            _ => Err(unsupported_op(op, self.name())),
+        }
+    }
+
+    fn to_number(&self, code: Code) -> Code {
+        let (one, zero) = (float(1.0), float(0.0));
+        call("np.where", &[code, one, zero])
+    }
+
+    fn to_bool(&self, code: Code) -> Code {
+        Code::boolean(format!("{} != 0", code.at(EQ + 1)), EQ)
+    }
+
+    fn select(&self, cond: Code, then: Code, other: Code) -> Code {
+        call("np.where", &[cond, then, other])
+    }
+
+    // 'and' doesn't broadcast over arrays
+    fn and(&self, lhs: Code, rhs: Code) -> Code {
+        let mut code = call("np.logical_and", &[lhs, rhs]);
+        code.boolean = true;
+        code
+    }
+}
+
+// Excel and LibreOffice formulas, variables are cell references
+struct Spreadsheet<'a> {
+    cells: &'a [(&'a str, &'a str)],
+}
+
+impl Target for Spreadsheet<'_> {
+    fn name(&self) -> &str {
+        "spreadsheets"
+    }
+
+    fn number(&self, x: f64) -> Result<Code, String> {
+        if !x.is_finite() {
+            return Err(format!("Can't write {} in {}", x, self.name()));
+        }
+        let prec = if x.is_sign_negative() { UNARY } else { ATOM };
+        Ok(Code::new(x.to_string(), prec))
+    }
+
+    fn constant(&self, name: &str) -> Option<Code> {
+        match name {
+            "pi" => Some(call("PI", &[])),
+            "e" => Some(call("EXP", &[Code::new("1".to_string(), ATOM)])),
+            _ => None,
+        }
+    }
+
+    fn parameter(&self, name: &str) -> Code {
+        let cell = self.cells.iter().find(|(var, _)| *var == name).unwrap().1;
+        Code::new(cell.to_string(), ATOM)
+    }
+
+    fn function(&self, name: &str, mut args: Vec<Code>) -> Result<Code, String> {
+        Ok(match (name, args.len()) {
+            ("sin", 1) | ("cos", 1) | ("abs", 1) => call(&name.to_uppercase(), &args),
+            // ATAN2 takes x first
+            ("atan2", 2) => {
+                args.swap(0, 1);
+                call("ATAN2", &args)
+            }
+            ("max", n) | ("min", n) if n > 0 => call(&name.to_uppercase(), &args),
+            ("nMPr", 2) => call("POWER", &args),
+            ("rand", 1) => infix(&args[0], "*", &call("RAND", &[]), MUL),
+            _ => return Err(unsupported_fn(name, self.name())),
+        })
+    }
+
+    fn binary(&self, op: &str, lhs: Code, rhs: Code) -> Result<Code, String> {
+        Ok(match op {
+            "+" | "-" => infix(&lhs, op, &rhs, ADD),
+            "*" | "/" => infix(&lhs, op, &rhs, MUL),
+            // MOD floors, the remainder truncates
+            "%" => {
+                let quotient = call("TRUNC", &[infix(&lhs, "/", &rhs, MUL)]);
+                infix(&lhs, "-", &infix(&rhs, "*", &quotient, MUL), ADD)
+            }
+            "^" => call("POWER", &[lhs, rhs]),
+            _ => return Err(unsupported_op(op, self.name())),
+        })
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Code) -> Result<Code, String> {
+        match (op, fixity) {
+            ("-", Fixity::Prefix) => Ok(negate(&operand)),
+            ("!", Fixity::Postfix) => Ok(call("FACT", &[operand])),
+            _ => Err(unsupported_op(op, self.name())),
+        }
+    }
+
+    // TRUE = 1 is false in a spreadsheet, so comparisons don't count as numbers
+    fn to_number(&self, code: Code) -> Code {
+        let (one, zero) = (
+            Code::new("1".to_string(), ATOM),
+            Code::new("0".to_string(), ATOM),
+        );
+        call("IF", &[code, one, zero])
+    }
+
+    fn to_bool(&self, code: Code) -> Code {
+        Code::boolean(format!("{} <> 0", code.at(CMP + 1)), CMP)
+    }
+
+    fn select(&self, cond: Code, then: Code, other: Code) -> Code {
+        call("IF", &[cond, then, other])
+    }
+
+    fn compare(&self, op: &str, lhs: Code, rhs: Code, _prec: u8) -> Code {
+        let op = match op {
+            "==" => "=",
+            "!=" => "<>",
+            _ => op,
+        };
+        Code::boolean(infix(&lhs, op, &rhs, CMP).text, CMP)
+    }
+
+    fn and(&self, lhs: Code, rhs: Code) -> Code {
+        let mut code = call("AND", &[lhs, rhs]);
+        code.boolean = true;
+        code
+    }
+}
+
+impl RPNExpr {
+    // the expression as Rust code over f64 variables
+    pub fn to_rust_expr(&self, vars: &[&str]) -> Result<String, String> {
+        Ok(generate(&Rust, &parameters(self, vars)?, vars)?.text)
+    }
+
+    // a Rust function of the variables in order, eg: 'fn f(x: f64) -> f64'
+    pub fn to_rust_fn(&self, name: &str, vars: &[&str]) -> Result<String, String> {
+        let body = self.to_rust_expr(vars)?;
+        let params: Vec<_> = vars.iter().map(|var| format!("{}: f64", var)).collect();
+        Ok(format!(
+            "fn {}({}) -> f64 {{\n    {}\n}}\n",
+            name,
+            params.join(", "),
+            body
+        ))
+    }
+
+    // the expression as C code over double variables
+    pub fn to_c_expr(&self, vars: &[&str]) -> Result<String, String> {
+        let c = C {
+            stdlib: Default::default(),
+        };
+        Ok(generate(&c, &parameters(self, vars)?, vars)?.text)
+    }
+
+    // a C function using math.h, eg: 'double f(double x)'
+    pub fn to_c_fn(&self, name: &str, vars: &[&str]) -> Result<String, String> {
+        let c = C {
+            stdlib: Default::default(),
+        };
+        let body = generate(&c, &parameters(self, vars)?, vars)?.text;
+        let mut includes = String::from("#include <math.h>\n");
+        if c.stdlib.get() {
+            includes.push_str("#include <stdlib.h>\n");
+        }
+        let params: Vec<_> = vars.iter().map(|var| format!("double {}", var)).collect();
+        Ok(format!(
+            "{}\ndouble {}({}) {{\n    return {};\n}}\n",
+            includes,
+            name,
+            params.join(", "),
+            body
+        ))
+    }
+
+    // the expression as GLSL code over float variables
+    pub fn to_glsl_expr(&self, vars: &[&str]) -> Result<String, String> {
+        let glsl = Shader { wgsl: false };
+        Ok(generate(&glsl, &parameters(self, vars)?, vars)?.text)
+    }
+
+    // a GLSL function, eg: 'float f(float x)'
+    pub fn to_glsl_fn(&self, name: &str, vars: &[&str]) -> Result<String, String> {
+        let body = self.to_glsl_expr(vars)?;
+        let params: Vec<_> = vars.iter().map(|var| format!("float {}", var)).collect();
+        Ok(format!(
+            "float {}({}) {{\n    return {};\n}}\n",
+            name,
+            params.join(", "),
+            body
+        ))
+    }
+
+    // the expression as WGSL code over f32 variables
+    pub fn to_wgsl_expr(&self, vars: &[&str]) -> Result<String, String> {
+        let wgsl = Shader { wgsl: true };
+        Ok(generate(&wgsl, &parameters(self, vars)?, vars)?.text)
+    }
+
+    // a WGSL function, eg: 'fn f(x: f32) -> f32'
+    pub fn to_wgsl_fn(&self, name: &str, vars: &[&str]) -> Result<String, String> {
+        let body = self.to_wgsl_expr(vars)?;
+        let params: Vec<_> = vars.iter().map(|var| format!("{}: f32", var)).collect();
+        Ok(format!(
+            "fn {}({}) -> f32 {{\n    return {};\n}}\n",
+            name,
+            params.join(", "),
+            body
+        ))
+    }
+
+    // the expression as vectorized NumPy code, for 'import numpy as np'
+    pub fn to_numpy_expr(&self, vars: &[&str]) -> Result<String, String> {
+        Ok(generate(&NumPy, &parameters(self, vars)?, vars)?.text)
+    }
+
+    // a Python function over NumPy arrays, eg: 'def f(x, y):'
+    pub fn to_numpy_fn(&self, name: &str, vars: &[&str]) -> Result<String, String> {
+        let body = self.to_numpy_expr(vars)?;
+        Ok(format!(
+            "import numpy as np\n\n\ndef {}({}):\n    return {}\n",
+            name,
+            vars.join(", "),
+            body
+        ))
+    }
+
+    // an Excel or LibreOffice formula, variables mapped to cells like 'A1'
+    pub fn to_formula(&self, cells: &[(&str, &str)]) -> Result<String, String> {
+        let vars: Vec<_> = cells.iter().map(|&(var, _)| var).collect();
+        let spreadsheet = Spreadsheet { cells };
+        let formula = generate(&spreadsheet, &parameters(self, &vars)?, &vars)?;
+        Ok(format!("={}", formula.text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ShuntingParser;
+
+    fn rust(expr: &str, vars: &[&str]) -> String {
+        let expr = ShuntingParser::parse_str(expr).unwrap();
+        expr.to_rust_expr(vars).unwrap_or_else(|err| err)
+    }
+
+    fn c(expr: &str, vars: &[&str]) -> String {
+        let expr = ShuntingParser::parse_str(expr).unwrap();
+        expr.to_c_expr(vars).unwrap_or_else(|err| err)
+    }
+
+    #[test]
+    fn rust_exprs() {
+        let xy = &["x", "y"];
+        assert_eq!(rust("x^2 + y", xy), "f64::powf(x, 2.0) + y");
+        assert_eq!(rust("x - (y - 1)", xy), "x - (y - 1.0)");
+        assert_eq!(rust("(x - y) - 1", xy), "x - y - 1.0");
+        assert_eq!(rust("x / (2 * y)", xy), "x / (2.0 * y)");
+        assert_eq!(rust("-(x + y) % 3", xy), "-(x + y) % 3.0");
+        assert_eq!(rust("-x^2", xy), "-f64::powf(x, 2.0)");
+        assert_eq!(rust("-(-x) - -1", xy), "-(-x) - -1.0");
+        assert_eq!(rust("(-x)^y^2", xy), "f64::powf(-x, f64::powf(y, 2.0))");
+        assert_eq!(
+            rust("max(x, y, 1) + min(x)", xy),
+            "f64::max(f64::max(x, y), 1.0) + x"
+        );
+        assert_eq!(
+            rust("abs(sin(x)) * atan2(y, x)", xy),
+            "f64::abs(f64::sin(x)) * f64::atan2(y, x)"
+        );
+        assert_eq!(rust("2 * pi * r", &["r"]), "2.0 * std::f64::consts::PI * r");
+        assert_eq!(rust("1e-7 + 0.5", xy), "1e-7 + 0.5");
+    }
+
+    #[test]
+    fn rust_conditions() {
+        let xy = &["x", "y"];
+        assert_eq!(rust("(x < y) * 2", xy), "f64::from(u8::from(x < y)) * 2.0");
+        assert_eq!(
+            rust("if(x < y && y, x, 0)", xy),
+            "if x < y && y != 0.0 { x } else { 0.0 }"
+        );
+        assert_eq!(
+            rust("1 + piecewise(x < 0, -x, x > 1, 1, x)", xy),
+            "1.0 + (if x < 0.0 { -x } else { if x > 1.0 { 1.0 } else { x } })"
+        );
+    }
+
+    #[test]
+    fn c_exprs() {
+        let xy = &["x", "y"];
+        assert_eq!(c("x^2 + y", xy), "pow(x, 2.0) + y");
+        assert_eq!(c("-(x + y) % 3", xy), "fmod(-(x + y), 3.0)");
+        assert_eq!(c("max(x, y, 1)", xy), "fmax(fmax(x, y), 1.0)");
+        assert_eq!(c("abs(x)", xy), "fabs(x)");
+        assert_eq!(c("-(-x) * -(-2)", xy), "-(-x) * -(-2.0)");
+        assert_eq!(c("(x + 1)! * 2", xy), "tgamma(x + 1.0 + 1.0) * 2.0");
+        assert_eq!(c("(x < y) * 2", xy), "(x < y) * 2.0");
+        assert_eq!(c("2 * if(x, y, x < y)", xy), "2.0 * (x != 0.0 ? y : x < y)");
+        assert_eq!(c("pi * e", xy), "M_PI * M_E");
+    }
+
+    #[test]
+    fn functions() {
+        let expr = ShuntingParser::parse_str("x^2 + y").unwrap();
+        assert_eq!(
+            expr.to_rust_fn("f", &["x", "y"]).unwrap(),
+            "fn f(x: f64, y: f64) -> f64 {\n    f64::powf(x, 2.0) + y\n}\n"
+        );
+        assert_eq!(
+            expr.to_c_fn("f", &["x", "y"]).unwrap(),
+            "#include <math.h>\n\ndouble f(double x, double y) {\n    return pow(x, 2.0) + y;\n}\n"
+        );
+        let expr = ShuntingParser::parse_str("rand(x)").unwrap();
+        assert_eq!(
+            expr.to_c_fn("noise", &["x"]).unwrap(),
+            "#include <math.h>\n#include <stdlib.h>\n\n\
+             double noise(double x) {\n    return x * (rand() / (RAND_MAX + 1.0));\n}\n"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(rust("x + z", &["x"]), "Unknown variable: z");
+        assert_eq!(
+            rust("rand(x)", &["x"]),
+            "Unsupported function in Rust: rand"
+        );
+        assert_eq!(rust("x!", &["x"]), "Unsupported operator in Rust: !");
+        assert_eq!(c("f(x)", &["x"]), "Unsupported function in C: f");
+        assert_eq!(c("x", &["x", "x"]), "Duplicate parameter: x");
+        assert_eq!(
+            c("sum(x^i, i, 0, 3)", &["x"]),
+            "Unsupported function in C: sum"
+        );
+    }
+
+    fn glsl(expr: &str, vars: &[&str]) -> String {
+        let expr = ShuntingParser::parse_str(expr).unwrap();
+        expr.to_glsl_expr(vars).unwrap_or_else(|err| err)
+    }
+
+    fn wgsl(expr: &str, vars: &[&str]) -> String {
+        let expr = ShuntingParser::parse_str(expr).unwrap();
+        expr.to_wgsl_expr(vars).unwrap_or_else(|err| err)
+    }
+
+    #[test]
+    fn shaders() {
+        let xy = &["x", "y"];
+        assert_eq!(glsl("x^2 + atan2(y, x)", xy), "x * x + atan(y, x)");
+        assert_eq!(wgsl("x^2 + atan2(y, x)", xy), "x * x + atan2(y, x)");
+        assert_eq!(
+            glsl("(x - 1)^3 / y^0.5", xy),
+            "(x - 1.0) * (x - 1.0) * (x - 1.0) / pow(y, 0.5)"
+        );
+        assert_eq!(glsl("-(-x) - -1", xy), "-(-x) - -1.0");
+        assert_eq!(wgsl("-(-x)^2", xy), "-(-x * -x)");
+        assert_eq!(
+            glsl("max(x, y, 0) * abs(x)", xy),
+            "max(max(x, y), 0.0) * abs(x)"
+        );
+        assert_eq!(
+            glsl("(x + 1) % y", xy),
+            "x + 1.0 - y * trunc((x + 1.0) / y)"
+        );
+        assert_eq!(wgsl("(x + 1) % y", xy), "(x + 1.0) % y");
+        assert_eq!(glsl("(x < y) / 2", xy), "float(x < y) / 2.0");
+        assert_eq!(wgsl("(x < y) / 2", xy), "f32(x < y) / 2.0");
+        assert_eq!(
+            glsl("if(x >= 1, 1, x) * 2", xy),
+            "(x >= 1.0 ? 1.0 : x) * 2.0"
+        );
+        assert_eq!(
+            wgsl("if(x >= 1, 1, x) * 2", xy),
+            "select(x, 1.0, x >= 1.0) * 2.0"
+        );
+        assert_eq!(glsl("2 * pi", xy), "2.0 * 3.141592653589793");
+    }
+
+    #[test]
+    fn shader_functions() {
+        let expr = ShuntingParser::parse_str("sin(t) * r").unwrap();
+        assert_eq!(
+            expr.to_glsl_fn("red", &["t", "r"]).unwrap(),
+            "float red(float t, float r) {\n    return sin(t) * r;\n}\n"
+        );
+        assert_eq!(
+            expr.to_wgsl_fn("red", &["t", "r"]).unwrap(),
+            "fn red(t: f32, r: f32) -> f32 {\n    return sin(t) * r;\n}\n"
+        );
+        assert_eq!(
+            glsl("rand(x)", &["x"]),
+            "Unsupported function in GLSL: rand"
+        );
+        assert_eq!(wgsl("x!", &["x"]), "Unsupported operator in WGSL: !");
+        assert_eq!(wgsl("f(x)", &["x"]), "Unsupported function in WGSL: f");
+    }
+
+    fn numpy(expr: &str, vars: &[&str]) -> String {
+        let expr = ShuntingParser::parse_str(expr).unwrap();
+        expr.to_numpy_expr(vars).unwrap_or_else(|err| err)
+    }
+
+    fn formula(expr: &str, cells: &[(&str, &str)]) -> String {
+        let expr = ShuntingParser::parse_str(expr).unwrap();
+        expr.to_formula(cells).unwrap_or_else(|err| err)
+    }
+
+    #[test]
+    fn numpy_exprs() {
+        let xy = &["x", "y"];
+        assert_eq!(numpy("sin(x)^2 + y", xy), "np.sin(x) ** 2.0 + y");
+        assert_eq!(numpy("-x^2", xy), "-x ** 2.0");
+        assert_eq!(numpy("(-x)^2", xy), "(-x) ** 2.0");
+        assert_eq!(numpy("-(-x^2)", xy), "-(-x ** 2.0)");
+        assert_eq!(numpy("(x^y)^2", xy), "(x ** y) ** 2.0");
+        assert_eq!(numpy("x^-y^2", xy), "x ** -y ** 2.0");
+        assert_eq!(
+            numpy("max(x, y, 0)", xy),
+            "np.maximum.reduce(np.broadcast_arrays(x, y, 0.0))"
+        );
+        assert_eq!(
+            numpy("x % y + atan2(y, x)", xy),
+            "np.fmod(x, y) + np.arctan2(y, x)"
+        );
+        assert_eq!(
+            numpy("(x < y) * pi", xy),
+            "np.where(x < y, 1.0, 0.0) * np.pi"
+        );
+        assert_eq!(
+            numpy("if(x > 0 && y, x, 0)", xy),
+            "np.where(np.logical_and(x > 0.0, y != 0), x, 0.0)"
+        );
+        assert_eq!(numpy("x!", xy), "Unsupported operator in NumPy: !");
+        let expr = ShuntingParser::parse_str("abs(x - y)").unwrap();
+        assert_eq!(
+            expr.to_numpy_fn("dist", xy).unwrap(),
+            "import numpy as np\n\n\ndef dist(x, y):\n    return np.abs(x - y)\n"
+        );
+    }
+
+    #[test]
+    fn formulas() {
+        let cells = &[("x", "A1"), ("y", "$B$2")];
+        assert_eq!(formula("sin(x)^2 + y", cells), "=POWER(SIN(A1), 2) + $B$2");
+        assert_eq!(
+            formula("max(x, y, 0.5) / -x", cells),
+            "=MAX(A1, $B$2, 0.5) / -A1"
+        );
+        assert_eq!(formula("-(x * y)", cells), "=-(A1 * $B$2)");
+        assert_eq!(formula("-(-x)", cells), "=-(-A1)");
+        assert_eq!(
+            formula("atan2(y, x) * pi", cells),
+            "=ATAN2(A1, $B$2) * PI()"
+        );
+        assert_eq!(formula("x % 3", cells), "=A1 - 3 * TRUNC(A1 / 3)");
+        assert_eq!(formula("(x == y) + 1", cells), "=IF(A1 = $B$2, 1, 0) + 1");
+        assert_eq!(
+            formula("if(x != 1 && y, x!, 0)", cells),
+            "=IF(AND(A1 <> 1, $B$2 <> 0), FACT(A1), 0)"
+        );
+        assert_eq!(formula("x + z", cells), "Unknown variable: z");
+    }
+}
@@ -0,0 +1,547 @@
+use crate::grammar::Fixity;
+use crate::parser::{binds_variable, is_lazy, ExprTree, RPNExpr};
+use crate::rpneval::{index_variable, integral, series_indices, MathContext, MAX_CALL_DEPTH};
+use crate::tokenizer::{MathToken, Radix};
+use std::collections::HashMap;
+
+// Derivatives of expressions, either symbolic as another expression or
+// evaluated along with the value using dual numbers
+
+fn number(x: f64) -> ExprTree {
+    ExprTree {
+        token: MathToken::Number(x, Radix::Dec),
+        args: Vec::new(),
+    }
+}
+
+fn node(token: MathToken, args: Vec<ExprTree>) -> ExprTree {
+    ExprTree { token, args }
+}
+
+fn call(name: &str, args: Vec<ExprTree>) -> ExprTree {
+    node(MathToken::Function(name.to_string(), args.len()), args)
+}
+
+fn value(tree: &ExprTree) -> Option<f64> {
+    match tree.token {
+        MathToken::Number(x, _) => Some(x),
+        MathToken::Integer(n, _) => Some(n as f64),
+        _ => None,
+    }
+}
+
+// the builders fold constants so derivatives don't fill up with '* 1' and
+// '+ 0'
+fn binary(op: &str, lhs: ExprTree, rhs: ExprTree) -> ExprTree {
+    let folded = match (op, value(&lhs), value(&rhs)) {
+        ("+", Some(x), Some(y)) => Some(number(x + y)),
+        ("-", Some(x), Some(y)) => Some(number(x - y)),
+        ("*", Some(x), Some(y)) => Some(number(x * y)),
+        ("+", Some(0.0), _) | ("*", Some(1.0), _) => Some(rhs.clone()),
+        ("+", _, Some(0.0)) | ("-", _, Some(0.0)) => Some(lhs.clone()),
+        ("*", _, Some(1.0)) | ("/", _, Some(1.0)) | ("^", _, Some(1.0)) => Some(lhs.clone()),
+        ("*", Some(0.0), _) | ("*", _, Some(0.0)) | ("/", Some(0.0), _) => Some(number(0.0)),
+        ("-", Some(0.0), _) => Some(negate(rhs.clone())),
+        _ => None,
+    };
+    folded.unwrap_or_else(|| node(MathToken::BOp(op.to_string()), vec![lhs, rhs]))
+}
+
+fn negate(tree: ExprTree) -> ExprTree {
+    match tree.token {
+        MathToken::UOp(ref op, Fixity::Prefix) if op == "-" => tree.args[0].clone(),
+        _ => match value(&tree) {
+            Some(x) => number(-x),
+            None => node(MathToken::UOp("-".to_string(), Fixity::Prefix), vec![tree]),
+        },
+    }
+}
+
+fn depends(tree: &ExprTree, var: &str) -> bool {
+    match tree.token {
+        MathToken::Variable(ref name) => name == var,
+        // an index of the same name hides 'var' in the summand
+        MathToken::Function(ref fname, 4) if binds_variable(fname) && index(tree) == Some(var) => {
+            depends(&tree.args[2], var) || depends(&tree.args[3], var)
+        }
+        _ => tree.args.iter().any(|arg| depends(arg, var)),
+    }
+}
+
+fn index(tree: &ExprTree) -> Option<&str> {
+    match tree.args[1].token {
+        MathToken::Variable(ref name) => Some(name),
+        _ => None,
+    }
+}
+
+fn derive(tree: &ExprTree, var: &str) -> Result<ExprTree, String> {
+    if !depends(tree, var) {
+        return Ok(number(0.0));
+    }
+    let args = &tree.args;
+    let d = |i: usize| derive(&args[i], var);
+    let arg = |i: usize| args[i].clone();
+    let cant = |what: &str| Err(format!("Can't differentiate {}", what));
+    Ok(match tree.token {
+        MathToken::Variable(_) => number(1.0),
+        MathToken::BOp(ref op) => match &op[..] {
+            "+" | "-" => binary(op, d(0)?, d(1)?),
+            "*" => binary("+", binary("*", d(0)?, arg(1)), binary("*", arg(0), d(1)?)),
+            "/" => {
+                let num = binary("-", binary("*", d(0)?, arg(1)), binary("*", arg(0), d(1)?));
+                binary("/", num, binary("^", arg(1), number(2.0)))
+            }
+            "^" => power(tree, var)?,
+            // flat but for the jumps
+            "<" | "<=" | ">" | ">=" | "==" | "!=" | "&&" => number(0.0),
+            _ => return cant(op),
+        },
+        MathToken::UOp(ref op, Fixity::Prefix) if op == "-" => negate(d(0)?),
+        MathToken::Function(ref name, arity) => match (&name[..], arity) {
+            ("sin", 1) => binary("*", call("cos", vec![arg(0)]), d(0)?),
+            ("cos", 1) => negate(binary("*", call("sin", vec![arg(0)]), d(0)?)),
+            // the sign, which like the dual numbers' is 0 at 0
+            ("abs", 1) => {
+                let sign = |op| binary(op, arg(0), number(0.0));
+                binary("*", binary("-", sign(">"), sign("<")), d(0)?)
+            }
+            // atan2(y, x)' = (x y' - y x') / (x^2 + y^2)
+            ("atan2", 2) => {
+                let num = binary("-", binary("*", arg(1), d(0)?), binary("*", arg(0), d(1)?));
+                let square = |i: usize| binary("^", arg(i), number(2.0));
+                binary("/", num, binary("+", square(0), square(1)))
+            }
+            // the derivative of whichever argument is picked
+            ("max", 1) | ("min", 1) => d(0)?,
+            ("max", _) | ("min", _) => {
+                let rest = call(name, args[1..].to_vec());
+                let picked = if name == "max" { ">=" } else { "<=" };
+                let cond = binary(picked, arg(0), rest.clone());
+                call("if", vec![cond, d(0)?, derive(&rest, var)?])
+            }
+            ("nMPr", 2) => power(tree, var)?,
+            ("if", 3) => call("if", vec![arg(0), d(1)?, d(2)?]),
+            // term by term, or under the integral sign, when the bounds are
+            // constant
+            ("sum", 4) | ("integrate", 4)
+                if index(tree).is_some() && !depends(&args[2], var) && !depends(&args[3], var) =>
+            {
+                call(name, vec![d(0)?, arg(1), arg(2), arg(3)])
+            }
+            ("piecewise", n) if n % 2 == 1 => {
+                let mut cases = Vec::new();
+                for (i, case) in args.iter().enumerate() {
+                    let is_value = i % 2 == 1 || i == n - 1;
+                    cases.push(if is_value { d(i)? } else { case.clone() });
+                }
+                call("piecewise", cases)
+            }
+            _ => return cant(name),
+        },
+        _ => return cant(&format!("{:?}", tree.token)),
+    })
+}
+
+// the power rule, exponents can't depend on 'var' as there's no log
+fn power(tree: &ExprTree, var: &str) -> Result<ExprTree, String> {
+    let (base, exp) = (&tree.args[0], &tree.args[1]);
+    if depends(exp, var) {
+        return Err("Can't differentiate a power with the variable in its exponent".to_string());
+    }
+    let lowered = binary("^", base.clone(), binary("-", exp.clone(), number(1.0)));
+    let outer = binary("*", exp.clone(), lowered);
+    Ok(binary("*", outer, derive(base, var)?))
+}
+
+impl RPNExpr {
+    // the derivative by 'var' as an expression, constants are folded but
+    // it's otherwise not simplified
+    pub fn derivative(&self, var: &str) -> Result<RPNExpr, String> {
+        Ok(derive(&self.to_tree()?, var)?.to_rpn())
+    }
+}
+
+// a value along with its derivative, arithmetic on them follows the chain
+// rule
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Dual {
+    value: f64,
+    slope: f64,
+}
+
+impl Dual {
+    fn constant(value: f64) -> Self {
+        Dual { value, slope: 0.0 }
+    }
+
+    fn chain(self, value: f64, slope: f64) -> Self {
+        Dual {
+            value,
+            slope: slope * self.slope,
+        }
+    }
+
+    // each term only when its slope isn't 0, so a constant base of 0 like
+    // the y in 'y^0.5' doesn't turn 0 * inf into NaN
+    fn powf(self, exp: Dual) -> Dual {
+        let value = self.value.powf(exp.value);
+        let mut slope = 0.0;
+        if self.slope != 0.0 {
+            slope += exp.value * self.value.powf(exp.value - 1.0) * self.slope;
+        }
+        if exp.slope != 0.0 {
+            slope += value * self.value.ln() * exp.slope;
+        }
+        Dual { value, slope }
+    }
+}
+
+impl MathContext {
+    // the value of 'rpn' and its derivative by 'var', at the value 'var'
+    // has in the context
+    pub fn eval_derivative(&self, rpn: &RPNExpr, var: &str) -> Result<(f64, f64), String> {
+        if !self.vars.contains_key(var) {
+            return Err(format!("Unknown Variable: {}", var));
+        }
+        let dual = self.eval_dual(&rpn.0, var, &HashMap::new(), 0)?;
+        Ok((dual.value, dual.slope))
+    }
+
+    fn eval_dual(
+        &self,
+        rpn: &[MathToken],
+        var: &str,
+        locals: &HashMap<&str, Dual>,
+        depth: usize,
+    ) -> Result<Dual, String> {
+        let missing = || "Wrong number of arguments".to_string();
+        let mut operands = Vec::new();
+        let mut quoted = Vec::new();
+
+        let mut tokens = rpn.iter();
+        while let Some(token) = tokens.next() {
+            let dual = match *token {
+                MathToken::Number(x, _) => Dual::constant(x),
+                MathToken::Integer(n, _) => Dual::constant(n as f64),
+                MathToken::Unit(ref unit) => Dual::constant(unit.scale),
+                MathToken::Variable(ref name) => match locals.get(&name[..]) {
+                    Some(dual) => *dual,
+                    None => match self.vars.get(name) {
+                        Some(&value) if name == var => Dual { value, slope: 1.0 },
+                        Some(&value) => Dual::constant(value),
+                        None => return Err(format!("Unknown Variable: {}", name)),
+                    },
+                },
+                MathToken::Quote(n) => {
+                    let rest = tokens.as_slice();
+                    if n > rest.len() {
+                        return Err(missing());
+                    }
+                    quoted.push(&rest[..n]);
+                    tokens = rest[n..].iter();
+                    continue;
+                }
+                MathToken::Function(ref fname, arity) if is_lazy(fname) => {
+                    if arity > quoted.len() {
+                        return Err(missing());
+                    }
+                    let cut = quoted.len() - arity;
+                    let args = quoted.split_off(cut);
+                    if binds_variable(fname) && arity == 4 {
+                        self.eval_series_dual(fname, &args, var, locals, depth)?
+                    } else if !binds_variable(fname) && arity % 2 == 1 {
+                        self.eval_piecewise_dual(&args, var, locals, depth)?
+                    } else {
+                        return Err(missing());
+                    }
+                }
+                _ => {
+                    let arity = token.arity();
+                    if arity > operands.len() {
+                        return Err(missing());
+                    }
+                    let cut = operands.len() - arity;
+                    let args = operands.split_off(cut);
+                    self.apply_dual(token, args, var, depth)?
+                }
+            };
+            operands.push(dual);
+        }
+        operands.pop().ok_or_else(missing)
+    }
+
+    fn eval_piecewise_dual(
+        &self,
+        args: &[&[MathToken]],
+        var: &str,
+        locals: &HashMap<&str, Dual>,
+        depth: usize,
+    ) -> Result<Dual, String> {
+        for case in args.chunks(2) {
+            match *case {
+                [cond, value] if self.eval_dual(cond, var, locals, depth)?.value != 0.0 => {
+                    return self.eval_dual(value, var, locals, depth)
+                }
+                [default] => return self.eval_dual(default, var, locals, depth),
+                _ => (),
+            }
+        }
+        unreachable!()
+    }
+
+    // sums and products term by term, integrals under the integral sign
+    // plus the slopes of the bounds times the integrand there
+    fn eval_series_dual(
+        &self,
+        fname: &str,
+        args: &[&[MathToken]],
+        var: &str,
+        locals: &HashMap<&str, Dual>,
+        depth: usize,
+    ) -> Result<Dual, String> {
+        let index = index_variable(fname, args[1])?;
+        let from = self.eval_dual(args[2], var, locals, depth)?;
+        let to = self.eval_dual(args[3], var, locals, depth)?;
+        let mut scope: HashMap<&str, Dual> = locals.iter().map(|(&k, &v)| (k, v)).collect();
+        let mut term = |x: f64| {
+            scope.insert(index, Dual::constant(x));
+            self.eval_dual(args[0], var, &scope, depth)
+        };
+        if fname == "integrate" {
+            let (value, _) = integral(&mut |x| Ok(term(x)?.value), from.value, to.value)?;
+            let (inner, _) = integral(&mut |x| Ok(term(x)?.slope), from.value, to.value)?;
+            let outer = term(to.value)?.value * to.slope - term(from.value)?.value * from.slope;
+            return Ok(Dual {
+                value,
+                slope: inner + outer,
+            });
+        }
+        let mut total = Dual::constant(if fname == "sum" { 0.0 } else { 1.0 });
+        for i in series_indices(from.value, to.value)? {
+            let term = term(i)?;
+            total = if fname == "sum" {
+                Dual {
+                    value: total.value + term.value,
+                    slope: total.slope + term.slope,
+                }
+            } else {
+                Dual {
+                    value: total.value * term.value,
+                    slope: total.slope * term.value + total.value * term.slope,
+                }
+            };
+        }
+        Ok(total)
+    }
+
+    fn apply_dual(
+        &self,
+        token: &MathToken,
+        args: Vec<Dual>,
+        var: &str,
+        depth: usize,
+    ) -> Result<Dual, String> {
+        let name = match *token {
+            MathToken::BOp(ref op) | MathToken::UOp(ref op, _) => match self.ops.get(op) {
+                Some(fname) => return self.call_dual(fname, args, var, depth),
+                None => op,
+            },
+            MathToken::Function(ref fname, _) => return self.call_dual(fname, args, var, depth),
+            _ => return Err(format!("Bad Token: {:?}", token)),
+        };
+        let dual = match (&name[..], &args[..]) {
+            ("+", &[l, r]) => Dual {
+                value: l.value + r.value,
+                slope: l.slope + r.slope,
+            },
+            ("-", &[l, r]) => Dual {
+                value: l.value - r.value,
+                slope: l.slope - r.slope,
+            },
+            ("*", &[l, r]) => Dual {
+                value: l.value * r.value,
+                slope: l.slope * r.value + l.value * r.slope,
+            },
+            ("/", &[l, r]) | ("in", &[l, r]) => Dual {
+                value: l.value / r.value,
+                slope: (l.slope * r.value - l.value * r.slope) / (r.value * r.value),
+            },
+            ("%", &[l, r]) => Dual {
+                value: l.value % r.value,
+                slope: l.slope - r.slope * (l.value / r.value).trunc(),
+            },
+            ("^", &[l, r]) => l.powf(r),
+            ("-", &[o]) => Dual {
+                value: -o.value,
+                slope: -o.slope,
+            },
+            // comparisons are flat but for the jumps
+            ("<", _) | ("<=", _) | (">", _) | (">=", _) | ("==", _) | ("!=", _) | ("&&", _) => {
+                let mut rpn: Vec<_> = args
+                    .iter()
+                    .map(|a| MathToken::Number(a.value, Radix::Dec))
+                    .collect();
+                rpn.push(token.clone());
+                Dual::constant(self.eval(&RPNExpr(rpn))?)
+            }
+            _ => return Err(format!("Can't differentiate {}", name)),
+        };
+        Ok(dual)
+    }
+
+    fn call_dual(
+        &self,
+        fname: &str,
+        args: Vec<Dual>,
+        var: &str,
+        depth: usize,
+    ) -> Result<Dual, String> {
+        if let Some(def) = self.funcs.get(fname) {
+            if args.len() != def.params.len() {
+                return Err(format!("Wrong number of arguments to {}", def.name));
+            }
+            if depth >= MAX_CALL_DEPTH {
+                return Err(format!("Recursion too deep in {}", def.name));
+            }
+            let locals = def.params.iter().map(|p| &p[..]).zip(args).collect();
+            return self.eval_dual(&def.body.0, var, &locals, depth + 1);
+        }
+        let dual = match (fname, &args[..]) {
+            ("sin", &[x]) => x.chain(x.value.sin(), x.value.cos()),
+            ("cos", &[x]) => x.chain(x.value.cos(), -x.value.sin()),
+            ("abs", &[x]) => {
+                let sign = if x.value == 0.0 {
+                    0.0
+                } else {
+                    x.value.signum()
+                };
+                x.chain(x.value.abs(), sign)
+            }
+            ("atan2", &[y, x]) => {
+                let norm = x.value * x.value + y.value * y.value;
+                Dual {
+                    value: y.value.atan2(x.value),
+                    slope: (x.value * y.slope - y.value * x.slope) / norm,
+                }
+            }
+            ("max", [first, rest @ ..]) => {
+                rest.iter()
+                    .fold(*first, |a, &b| if b.value > a.value { b } else { a })
+            }
+            ("min", [first, rest @ ..]) => {
+                rest.iter()
+                    .fold(*first, |a, &b| if b.value < a.value { b } else { a })
+            }
+            ("nMPr", &[n, r]) => n.powf(r),
+            ("sin", _)
+            | ("cos", _)
+            | ("abs", _)
+            | ("atan2", _)
+            | ("max", _)
+            | ("min", _)
+            | ("nMPr", _) => return Err("Wrong number of arguments".to_string()),
+            ("rand", _) => return Err("Can't differentiate rand".to_string()),
+            _ => return Err(format!("Unknown function: {}", fname)),
+        };
+        Ok(dual)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::parser::ShuntingParser;
+
+    fn derivative(expr: &str) -> Result<String, String> {
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            ..Grammar::default()
+        };
+        let expr = ShuntingParser::parse_str_with(expr, &grammar).unwrap();
+        Ok(expr.derivative("x")?.to_string())
+    }
+
+    #[test]
+    fn symbolic() {
+        assert_eq!(derivative("x^3 - 2x"), Ok("3 * x ^ 2 - 2".to_string()));
+        assert_eq!(derivative("y + 4"), Ok("0".to_string()));
+        assert_eq!(derivative("sin(2x)"), Ok("cos(2 * x) * 2".to_string()));
+        assert_eq!(derivative("-cos(x)"), Ok("sin(x)".to_string()));
+        assert_eq!(derivative("1 / x"), Ok("-1 / x ^ 2".to_string()));
+        assert_eq!(
+            derivative("if(x > 0, x^2, -x)"),
+            Ok("if(x > 0, 2 * x, -1)".to_string())
+        );
+        assert_eq!(
+            derivative("max(x, 1)"),
+            Ok("if(x >= max(1), 1, 0)".to_string())
+        );
+        assert_eq!(
+            derivative("abs(x^2 - 1)"),
+            Ok("((x ^ 2 - 1 > 0) - (x ^ 2 - 1 < 0)) * (2 * x)".to_string())
+        );
+        assert_eq!(
+            derivative("2^x"),
+            Err("Can't differentiate a power with the variable in its exponent".to_string())
+        );
+        assert_eq!(derivative("f(x)"), Err("Can't differentiate f".to_string()));
+        assert_eq!(
+            derivative("sum(x^k, k, 1, 3) + sum(x, x, 1, 3)"),
+            Ok("sum(k * x ^ (k - 1), k, 1, 3)".to_string())
+        );
+        assert_eq!(
+            derivative("integrate(t * x^2, t, 0, 1)"),
+            Ok("integrate(t * (2 * x), t, 0, 1)".to_string())
+        );
+        assert_eq!(
+            derivative("prod(x, k, 1, 3)"),
+            Err("Can't differentiate prod".to_string())
+        );
+    }
+
+    #[test]
+    fn dual() {
+        let mut cx = MathContext::new();
+        cx.define("f(t) = t * sin(t) + 2^t").unwrap();
+        cx.setvar("x", 1.5);
+        let expr = ShuntingParser::parse_str("f(x) / x + if(x < 2, x^2, 0)").unwrap();
+        let (value, slope) = cx.eval_derivative(&expr, "x").unwrap();
+        let x: f64 = 1.5;
+        let f = x * x.sin() + x.exp2();
+        let df = x.sin() + x * x.cos() + x.exp2() * 2f64.ln();
+        assert_eq!(value, cx.eval(&expr).unwrap());
+        assert!((slope - ((df * x - f) / (x * x) + 2.0 * x)).abs() < 1e-12);
+        assert_eq!(
+            cx.eval_derivative(&expr, "y"),
+            Err("Unknown Variable: y".to_string())
+        );
+        // the fundamental theorem of calculus, and the product rule
+        let expr = ShuntingParser::parse_str("integrate(t^2 + x, t, 0, x)").unwrap();
+        let (value, slope) = cx.eval_derivative(&expr, "x").unwrap();
+        assert!((value - (x.powi(3) / 3.0 + x * x)).abs() < 1e-10);
+        assert!((slope - (x * x + 2.0 * x)).abs() < 1e-10);
+        let expr = ShuntingParser::parse_str("prod(x + k, k, 1, 2) + sum(x, x, 1, 2)").unwrap();
+        let (value, slope) = cx.eval_derivative(&expr, "x").unwrap();
+        assert_eq!((value, slope), ((x + 1.0) * (x + 2.0) + 3.0, 2.0 * x + 3.0));
+
+        // both agree on the slope of abs at 0, and a constant base of 0 has
+        // no slope to give NaN
+        cx.setvar("x", 0.0);
+        cx.setvar("y", 0.0);
+        let expr = ShuntingParser::parse_str("abs(x) + y^0.5 + x").unwrap();
+        assert_eq!(cx.eval_derivative(&expr, "x"), Ok((0.0, 1.0)));
+        let symbolic = expr.derivative("x").unwrap();
+        assert_eq!(cx.eval(&symbolic), Ok(1.0));
+        cx.setvar("x", -2.0);
+        assert_eq!(cx.eval_derivative(&expr, "x"), Ok((0.0, 0.0)));
+        assert_eq!(cx.eval(&expr.derivative("x").unwrap()), Ok(0.0));
+
+        let expr = ShuntingParser::parse_str("rand(x)").unwrap();
+        assert_eq!(
+            cx.eval_derivative(&expr, "x"),
+            Err("Can't differentiate rand".to_string())
+        );
+    }
+}
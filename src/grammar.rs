@@ -0,0 +1,246 @@
+use crate::tokenizer::MathToken;
+use crate::units::Units;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Fixity {
+    Prefix,  // -x
+    Infix,   // x + y
+    Postfix, // x!
+}
+
+// An operator the tokenizer recognizes and the parser knows how to bind,
+// symbols made of identifier chars like 'mod' are read as whole words
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operator {
+    pub symbol: String,
+    pub fixity: Fixity,
+    pub precedence: usize,
+    pub assoc: Assoc,
+}
+
+impl Operator {
+    pub fn infix(symbol: &str, precedence: usize, assoc: Assoc) -> Self {
+        Operator {
+            symbol: symbol.to_string(),
+            fixity: Fixity::Infix,
+            precedence,
+            assoc,
+        }
+    }
+
+    pub fn prefix(symbol: &str, precedence: usize) -> Self {
+        Operator {
+            symbol: symbol.to_string(),
+            fixity: Fixity::Prefix,
+            precedence,
+            assoc: Assoc::Right,
+        }
+    }
+
+    pub fn postfix(symbol: &str, precedence: usize) -> Self {
+        Operator {
+            symbol: symbol.to_string(),
+            fixity: Fixity::Postfix,
+            precedence,
+            assoc: Assoc::Left,
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        match self.fixity {
+            Fixity::Infix => 2,
+            Fixity::Prefix | Fixity::Postfix => 1,
+        }
+    }
+
+    // word operators are matched like identifiers, eg: 'xor' but not 'xorg'
+    pub fn is_word(&self) -> bool {
+        self.symbol
+            .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+    }
+}
+
+// The operators of a grammar, a symbol may appear once per fixity, eg: both
+// unary and binary '-'. A higher precedence binds tighter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperatorTable(Vec<Operator>);
+
+impl Default for OperatorTable {
+    fn default() -> Self {
+        // You can play with the relation between exponentiation an unary - by
+        // a. switching order in which the lexer tokenizes, if it tries
+        // operators first then '-' will never be the negative part of number,
+        // else if numbers are tried before operators, - can only be unary
+        // for non-numeric tokens (eg: -(3)).
+        // b. changing the precedence of '-' respect to '^'
+        // If '-' has lower precedence then 2^-3 will fail to evaluate if the
+        // '-' isn't part of the number because ^ will only find 1 operator
+        OperatorTable(vec![
+            Operator::infix("&&", 5, Assoc::Left),
+            // comparisons don't chain, 'a < b < c' is an error unless
+            // Grammar::chained_comparisons is set
+            Operator::infix("<", 10, Assoc::None),
+            Operator::infix("<=", 10, Assoc::None),
+            Operator::infix(">", 10, Assoc::None),
+            Operator::infix(">=", 10, Assoc::None),
+            Operator::infix("==", 10, Assoc::None),
+            Operator::infix("!=", 10, Assoc::None),
+            Operator::infix("+", 20, Assoc::Left),
+            Operator::infix("-", 20, Assoc::Left),
+            Operator::infix("*", 30, Assoc::Left),
+            Operator::infix("/", 30, Assoc::Left),
+            Operator::infix("%", 30, Assoc::Left),
+            Operator::prefix("-", 50), // unary minus
+            Operator::infix("^", 50, Assoc::Right),
+            Operator::postfix("!", 60), // factorial
+        ])
+    }
+}
+
+impl OperatorTable {
+    // a table without any operators
+    pub fn new() -> Self {
+        OperatorTable(Vec::new())
+    }
+
+    // the default operators plus the bitwise ones
+    pub fn integer() -> Self {
+        let mut table = Self::default();
+        table.add(Operator::infix("|", 12, Assoc::Left));
+        table.add(Operator::infix("xor", 13, Assoc::Left));
+        table.add(Operator::infix("&", 14, Assoc::Left));
+        table.add(Operator::infix("<<", 16, Assoc::Left));
+        table.add(Operator::infix(">>", 16, Assoc::Left));
+        table.add(Operator::prefix("~", 50)); // bitwise not
+        table
+    }
+
+    // add an operator, replacing the one with the same symbol and fixity
+    pub fn add(&mut self, op: Operator) {
+        self.remove(&op.symbol, op.fixity);
+        self.0.push(op);
+    }
+
+    pub fn remove(&mut self, symbol: &str, fixity: Fixity) -> Option<Operator> {
+        let pos = self
+            .0
+            .iter()
+            .position(|op| op.symbol == symbol && op.fixity == fixity)?;
+        Some(self.0.remove(pos))
+    }
+
+    pub fn get(&self, symbol: &str, fixity: Fixity) -> Option<&Operator> {
+        self.0
+            .iter()
+            .find(|op| op.symbol == symbol && op.fixity == fixity)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Operator> {
+        self.0.iter()
+    }
+
+    // the operator a token stands for
+    pub fn lookup(&self, token: &MathToken) -> Option<&Operator> {
+        match *token {
+            MathToken::BOp(ref op) => self.get(op, Fixity::Infix),
+            MathToken::UOp(ref op, fixity) => self.get(op, fixity),
+            _ => None,
+        }
+    }
+
+    // how tightly a token binds, operands and function calls bind tighter
+    // than any operator, operators missing from the table looser so they
+    // get parenthesized when printed
+    pub fn precedence(&self, token: &MathToken) -> (usize, Assoc) {
+        match *token {
+            MathToken::OParen => (0, Assoc::Left),
+            MathToken::BOp(_) | MathToken::UOp(..) => match self.lookup(token) {
+                Some(op) => (op.precedence, op.assoc),
+                None => (0, Assoc::None),
+            },
+            MathToken::Function(_, _) => (usize::MAX, Assoc::Left),
+            _ => (usize::MAX, Assoc::None),
+        }
+    }
+}
+
+// Syntax options shared by the tokenizer and the parser, the default
+// grammar is the plain one understood by ShuntingParser::parse_str
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Grammar {
+    // the operators and how they bind, see OperatorTable::integer for the
+    // bitwise ones
+    pub operators: OperatorTable,
+    // read juxtaposed operands like '2x', '3(x+1)' or 'x y' as products
+    pub implicit_multiplication: bool,
+    // scale factors for suffixes right after a number, eg: 4.7k or 220n,
+    // a suffix wins over implicit multiplication so '2k' is 2000, not 2*k
+    pub suffixes: Vec<(char, f64)>,
+    // integer literals are read exactly, see IntContext
+    pub integer: bool,
+    // read 'a < b < c' as 'a < b && b < c', like python does, except that
+    // 'b' is copied so it's evaluated twice, eg: in '0 < rand(1) < 0.5'
+    pub chained_comparisons: bool,
+    // units that may follow a number, eg: '9.81 m/s^2', see Grammar::units
+    pub units: Option<Units>,
+}
+
+impl Grammar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the grammar for bitfield and address computations
+    pub fn integer() -> Self {
+        Grammar {
+            operators: OperatorTable::integer(),
+            integer: true,
+            ..Self::default()
+        }
+    }
+
+    // quantities like '5 kg * 9.81 m/s^2 in N' with the SI units, 'in'
+    // converts to the unit after it and binds looser than anything else
+    pub fn units() -> Self {
+        let mut operators = OperatorTable::default();
+        operators.add(Operator::infix("in", 1, Assoc::Left));
+        Grammar {
+            operators,
+            units: Some(Units::si()),
+            ..Self::default()
+        }
+    }
+
+    // SI prefixes as suffixes, 'u' stands in for 'µ' and 'E' (exa) is left
+    // out as it would read '2E3' as an exponent
+    pub fn si_suffixes() -> Vec<(char, f64)> {
+        vec![
+            ('y', 1e-24),
+            ('z', 1e-21),
+            ('a', 1e-18),
+            ('f', 1e-15),
+            ('p', 1e-12),
+            ('n', 1e-9),
+            ('u', 1e-6),
+            ('µ', 1e-6),
+            ('m', 1e-3),
+            ('k', 1e3),
+            ('M', 1e6),
+            ('G', 1e9),
+            ('T', 1e12),
+            ('P', 1e15),
+            ('Z', 1e21),
+            ('Y', 1e24),
+        ]
+    }
+}
@@ -0,0 +1,109 @@
+use crate::parser::RPNExpr;
+use crate::rpnprint::{print_integer, print_number, AST};
+use crate::tokenizer::MathToken;
+
+// Views of the expression tree to debug how an expression was parsed
+
+fn label(token: &MathToken) -> String {
+    match *token {
+        MathToken::Number(x, radix) => print_number(x, radix),
+        MathToken::Integer(n, radix) => print_integer(n, radix),
+        MathToken::Variable(ref name) | MathToken::Function(ref name, _) => name.to_string(),
+        MathToken::Unit(ref unit) => unit.name.to_string(),
+        MathToken::UOp(ref op, _) | MathToken::BOp(ref op) => op.to_string(),
+        _ => unreachable!(),
+    }
+}
+
+fn sexpr(ast: &AST) -> String {
+    match ast {
+        AST::Leaf(token) => label(token),
+        AST::Node(token, args) => {
+            let args: Vec<_> = args.iter().map(sexpr).collect();
+            if args.is_empty() {
+                format!("({})", label(token))
+            } else {
+                format!("({} {})", label(token), args.join(" "))
+            }
+        }
+    }
+}
+
+// write nodes and edges of the subtree, returns the id of its root
+fn dot(ast: &AST, next_id: &mut usize, out: &mut Vec<String>) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    let (token, shape) = match ast {
+        AST::Leaf(token) => (token, "box"),
+        AST::Node(token, _) => (token, "ellipse"),
+    };
+    let text = label(token).replace('\\', "\\\\").replace('"', "\\\"");
+    out.push(format!(
+        "    n{} [label=\"{}\", shape={}];",
+        id, text, shape
+    ));
+    if let AST::Node(_, args) = ast {
+        for arg in args.iter() {
+            let child = dot(arg, next_id, out);
+            out.push(format!("    n{} -> n{};", id, child));
+        }
+    }
+    id
+}
+
+impl RPNExpr {
+    // the tree as an s-expression, eg: (+ 3 (* 4 2))
+    pub fn to_sexpr(&self) -> Result<String, String> {
+        Ok(sexpr(&self.build_ast()?))
+    }
+
+    // the tree as a Graphviz digraph, operands are drawn left to right
+    pub fn to_dot(&self) -> Result<String, String> {
+        let mut lines = vec!["digraph {".to_string(), "    ordering=out;".to_string()];
+        dot(&self.build_ast()?, &mut 0, &mut lines);
+        lines.push("}".to_string());
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ShuntingParser;
+
+    #[test]
+    fn sexpr() {
+        let expr = ShuntingParser::parse_str("3+4*2/-(1-5)^2^3").unwrap();
+        assert_eq!(
+            expr.to_sexpr().unwrap(),
+            "(+ 3 (/ (* 4 2) (- (^ (- 1 5) (^ 2 3)))))"
+        );
+        let expr = ShuntingParser::parse_str("max(x, 7!, f()) - if(x, 1, 0x1F)").unwrap();
+        assert_eq!(
+            expr.to_sexpr().unwrap(),
+            "(- (max x (! 7) (f)) (if x 1 0x1F))"
+        );
+        let empty = ShuntingParser::parse_str("").unwrap();
+        assert_eq!(empty.to_sexpr(), Err("Empty expression".to_string()));
+        assert_eq!(empty.to_dot(), Err("Empty expression".to_string()));
+    }
+
+    #[test]
+    fn dot() {
+        let expr = ShuntingParser::parse_str("-x * sin(2)").unwrap();
+        let expect = [
+            "digraph {",
+            "    ordering=out;",
+            "    n0 [label=\"*\", shape=ellipse];",
+            "    n1 [label=\"-\", shape=ellipse];",
+            "    n2 [label=\"x\", shape=box];",
+            "    n1 -> n2;",
+            "    n0 -> n1;",
+            "    n3 [label=\"sin\", shape=ellipse];",
+            "    n4 [label=\"2\", shape=box];",
+            "    n3 -> n4;",
+            "    n0 -> n3;",
+            "}",
+        ];
+        assert_eq!(expr.to_dot(), Ok(expect.join("\n")));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::parser::{is_lazy, RPNExpr};
+use crate::rpneval::{index_variable, MAX_TERMS};
+use crate::tokenizer::MathToken;
+use std::collections::HashMap;
+
+// a shorthand for checking number of arguments before eval_fn
+macro_rules! nargs {
+    ($argcheck:expr, $ifok:expr) => {
+        if $argcheck {
+            $ifok
+        } else {
+            Err("Wrong number of arguments".to_string())
+        }
+    };
+}
+
+// The integer type expressions are evaluated in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntType {
+    I64,
+    U64,
+}
+
+// What to do when a result doesn't fit in the integer type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Wrapping,   // keep the low 64 bits, like two's complement hardware
+    Checked,    // fail the evaluation
+    Saturating, // clamp to the closest representable value
+}
+
+// Evaluates expressions parsed with Grammar::integer on 64 bit integers.
+// Variables hold bit patterns, read back as signed or unsigned per int_type,
+// results are returned as i128 so that both i64 and u64 values fit.
+#[derive(Debug, Clone)]
+pub struct IntContext {
+    pub vars: HashMap<String, u64>,
+    pub int_type: IntType,
+    pub overflow: Overflow,
+}
+
+impl IntContext {
+    pub fn new(int_type: IntType, overflow: Overflow) -> IntContext {
+        IntContext {
+            vars: HashMap::new(),
+            int_type,
+            overflow,
+        }
+    }
+
+    pub fn setvar(&mut self, var: &str, val: impl Into<i128>) -> Result<(), String> {
+        let val = self.fit(val.into())?;
+        self.vars.insert(var.to_string(), val as u64);
+        Ok(())
+    }
+
+    pub fn eval(&self, rpn: &RPNExpr) -> Result<i128, String> {
+        self.eval_tokens(&rpn.0)
+    }
+
+    fn eval_tokens(&self, rpn: &[MathToken]) -> Result<i128, String> {
+        let mut operands = Vec::new();
+        let mut quoted = Vec::new();
+
+        let mut tokens = rpn.iter();
+        while let Some(token) = tokens.next() {
+            match *token {
+                MathToken::Integer(bits, _) => operands.push(self.read_bits(bits)),
+                MathToken::Number(num, _) if num.is_finite() && num.fract() == 0.0 => {
+                    operands.push(self.fit(num as i128)?)
+                }
+                MathToken::Number(num, _) => return Err(format!("Not an integer: {}", num)),
+                MathToken::Variable(ref var) => match self.vars.get(var) {
+                    Some(bits) => operands.push(self.read_bits(*bits)),
+                    None => return Err(format!("Unknown Variable: {}", var)),
+                },
+                MathToken::BOp(ref op) => {
+                    let r = operands
+                        .pop()
+                        .ok_or_else(|| "Wrong number of arguments".to_string())?;
+                    let l = operands
+                        .pop()
+                        .ok_or_else(|| "Wrong number of arguments".to_string())?;
+                    operands.push(self.eval_bop(op, l, r)?);
+                }
+                MathToken::UOp(ref op, _) => {
+                    let o = operands
+                        .pop()
+                        .ok_or_else(|| "Wrong number of arguments".to_string())?;
+                    match &op[..] {
+                        "-" => operands.push(self.fit(-o)?),
+                        "~" => operands.push(self.read_bits(!(o as u64))),
+                        _ => return Err(format!("Bad Token: {}", op.clone())),
+                    }
+                }
+                MathToken::Quote(n) => {
+                    let rest = tokens.as_slice();
+                    if n > rest.len() {
+                        return Err("Wrong number of arguments".to_string());
+                    }
+                    quoted.push(&rest[..n]);
+                    tokens = rest[n..].iter();
+                }
+                MathToken::Function(ref fname, arity) if is_lazy(fname) => {
+                    if arity > quoted.len() {
+                        return Err("Wrong number of arguments".to_string());
+                    }
+                    let cut = quoted.len() - arity;
+                    let args = quoted.split_off(cut);
+                    operands.push(self.eval_lazy(fname, &args)?)
+                }
+                MathToken::Function(ref fname, arity) => {
+                    if arity > operands.len() {
+                        return Err("Wrong number of arguments".to_string());
+                    }
+                    let cut = operands.len() - arity;
+                    let args = operands.split_off(cut);
+                    operands.push(self.eval_fn(fname, args)?)
+                }
+                _ => return Err(format!("Bad Token: {:?}", *token)),
+            }
+        }
+        operands
+            .pop()
+            .ok_or_else(|| "Wrong number of arguments".to_string())
+    }
+
+    fn eval_bop(&self, op: &str, l: i128, r: i128) -> Result<i128, String> {
+        match op {
+            "+" => self.fit(l + r),
+            "-" => self.fit(l - r),
+            "*" => self.mul(l, r),
+            "/" if r == 0 => Err("Division by zero".to_string()),
+            "/" => self.fit(l / r),
+            "%" if r == 0 => Err("Division by zero".to_string()),
+            "%" => self.fit(l % r),
+            "^" => self.pow(l, r),
+            "&" => Ok(l & r),
+            "|" => Ok(l | r),
+            "xor" => Ok(l ^ r),
+            "<<" => Ok(self.read_bits((l as u64) << self.shift(r)?)),
+            ">>" => match self.int_type {
+                IntType::I64 => Ok(((l as i64) >> self.shift(r)?) as i128),
+                IntType::U64 => Ok(((l as u64) >> self.shift(r)?) as i128),
+            },
+            "<" => Ok((l < r) as i128),
+            "<=" => Ok((l <= r) as i128),
+            ">" => Ok((l > r) as i128),
+            ">=" => Ok((l >= r) as i128),
+            "==" => Ok((l == r) as i128),
+            "!=" => Ok((l != r) as i128),
+            "&&" => Ok((l != 0 && r != 0) as i128),
+            _ => Err(format!("Bad Token: {}", op)),
+        }
+    }
+
+    fn eval_fn(&self, fname: &str, args: Vec<i128>) -> Result<i128, String> {
+        match fname {
+            "abs" => nargs!(args.len() == 1, self.fit(args[0].abs())),
+            "max" => nargs!(!args.is_empty(), Ok(*args.iter().max().unwrap())),
+            "min" => nargs!(!args.is_empty(), Ok(*args.iter().min().unwrap())),
+            _ => Err(format!("Unknown function: {}", fname)),
+        }
+    }
+
+    // lazy functions get their arguments unevaluated, see MathContext
+    fn eval_lazy(&self, fname: &str, args: &[&[MathToken]]) -> Result<i128, String> {
+        match fname {
+            "if" => nargs!(args.len() == 3, self.eval_piecewise(args)),
+            "piecewise" => nargs!(args.len() % 2 == 1, self.eval_piecewise(args)),
+            "sum" | "prod" => nargs!(args.len() == 4, self.eval_series(fname, args)),
+            _ => Err(format!("Unknown function: {}", fname)),
+        }
+    }
+
+    // as in MathContext, with the index stepping through the integers
+    fn eval_series(&self, fname: &str, args: &[&[MathToken]]) -> Result<i128, String> {
+        let index = index_variable(fname, args[1])?;
+        let from = self.eval_tokens(args[2])?;
+        let to = self.eval_tokens(args[3])?;
+        if to - from >= MAX_TERMS as i128 {
+            return Err(format!("Too many terms: {}", to - from + 1));
+        }
+        let mut cx = self.clone();
+        let mut total = if fname == "sum" { 0 } else { 1 };
+        for i in from..=to {
+            cx.setvar(index, i)?;
+            let term = cx.eval_tokens(args[0])?;
+            total = if fname == "sum" {
+                self.fit(total + term)?
+            } else {
+                self.mul(total, term)?
+            };
+        }
+        Ok(total)
+    }
+
+    fn eval_piecewise(&self, args: &[&[MathToken]]) -> Result<i128, String> {
+        for case in args.chunks(2) {
+            match *case {
+                [cond, value] if self.eval_tokens(cond)? != 0 => return self.eval_tokens(value),
+                [default] => return self.eval_tokens(default),
+                _ => (),
+            }
+        }
+        unreachable!()
+    }
+
+    // read a 64 bit pattern as int_type
+    fn read_bits(&self, bits: u64) -> i128 {
+        match self.int_type {
+            IntType::I64 => bits as i64 as i128,
+            IntType::U64 => bits as i128,
+        }
+    }
+
+    // bring an exact result into the range of int_type as per overflow
+    fn fit(&self, val: i128) -> Result<i128, String> {
+        let (min, max) = match self.int_type {
+            IntType::I64 => (i64::MIN as i128, i64::MAX as i128),
+            IntType::U64 => (0, u64::MAX as i128),
+        };
+        match self.overflow {
+            _ if min <= val && val <= max => Ok(val),
+            Overflow::Wrapping => Ok(self.read_bits(val as u64)),
+            Overflow::Checked => Err("Integer overflow".to_string()),
+            Overflow::Saturating => Ok(val.clamp(min, max)),
+        }
+    }
+
+    fn mul(&self, l: i128, r: i128) -> Result<i128, String> {
+        match l.checked_mul(r) {
+            Some(val) => self.fit(val),
+            // the low 64 bits of the product survive wrapping in i128
+            None if self.overflow == Overflow::Wrapping => self.fit(l.wrapping_mul(r)),
+            None if (l < 0) != (r < 0) => self.fit(i128::MIN),
+            None => self.fit(i128::MAX),
+        }
+    }
+
+    // exponentiation by squaring, overflowing as per mul
+    fn pow(&self, base: i128, exp: i128) -> Result<i128, String> {
+        if exp < 0 {
+            return Err("Negative exponent".to_string());
+        }
+        let (mut result, mut base, mut exp) = (1, base, exp);
+        loop {
+            if exp & 1 == 1 {
+                result = self.mul(result, base)?;
+            }
+            exp >>= 1;
+            if exp == 0 {
+                return Ok(result);
+            }
+            base = self.mul(base, base)?;
+        }
+    }
+
+    // shift amounts past the width wrap around or fail
+    fn shift(&self, amount: i128) -> Result<u32, String> {
+        match self.overflow {
+            _ if (0..64).contains(&amount) => Ok(amount as u32),
+            Overflow::Wrapping => Ok((amount & 63) as u32),
+            _ => Err(format!("Shift out of range: {}", amount)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IntContext, IntType, Overflow};
+    use crate::grammar::Grammar;
+    use crate::parser::ShuntingParser;
+
+    fn eval(cx: &IntContext, expr: &str) -> Result<i128, String> {
+        cx.eval(&ShuntingParser::parse_str_with(expr, &Grammar::integer()).unwrap())
+    }
+
+    #[test]
+    fn bitfields() {
+        let mut cx = IntContext::new(IntType::U64, Overflow::Wrapping);
+        cx.setvar("reg", 0xDEAD_BEEFu32).unwrap();
+        assert_eq!(eval(&cx, "(reg >> 8) & 0xFF"), Ok(0xBE));
+        assert_eq!(eval(&cx, "reg & ~0xFFFF | 0x1234"), Ok(0xDEAD_1234));
+        assert_eq!(eval(&cx, "reg xor reg << 4 & 0xF0"), Ok(0xDEAD_BE1F));
+        assert_eq!(eval(&cx, "1 << 3 + 1"), Ok(16));
+        assert_eq!(eval(&cx, "0xFFFF_FFFF_FFFF_FFFF"), Ok(u64::MAX as i128));
+        assert_eq!(eval(&cx, "7 / 2 + 7 % 2 + 2^10"), Ok(1028));
+        assert_eq!(eval(&cx, "if(reg > 0, max(1, 5, 3), 1 / 0)"), Ok(5));
+    }
+
+    #[test]
+    fn signed() {
+        let cx = IntContext::new(IntType::I64, Overflow::Checked);
+        assert_eq!(eval(&cx, "-7 / 2"), Ok(-3));
+        assert_eq!(eval(&cx, "-16 >> 2"), Ok(-4));
+        assert_eq!(eval(&cx, "~0"), Ok(-1));
+        assert_eq!(eval(&cx, "0xFFFF_FFFF_FFFF_FFFF"), Ok(-1));
+        assert_eq!(eval(&cx, "abs(-3) * -2^3"), Ok(-24));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut cx = IntContext::new(IntType::U64, Overflow::Wrapping);
+        assert_eq!(eval(&cx, "0 - 1"), Ok(u64::MAX as i128));
+        assert_eq!(eval(&cx, "2^64 + 3^41"), Ok(3i128.pow(41) % (1 << 64)));
+        assert_eq!(eval(&cx, "1 << 65"), Ok(2));
+        cx.overflow = Overflow::Saturating;
+        assert_eq!(eval(&cx, "0 - 1"), Ok(0));
+        assert_eq!(eval(&cx, "2^64 + 3^41"), Ok(u64::MAX as i128));
+        cx.overflow = Overflow::Checked;
+        assert_eq!(eval(&cx, "0 - 1"), Err("Integer overflow".to_string()));
+        assert_eq!(
+            eval(&cx, "1 << 64"),
+            Err("Shift out of range: 64".to_string())
+        );
+
+        let mut cx = IntContext::new(IntType::I64, Overflow::Wrapping);
+        assert_eq!(eval(&cx, "0x7FFF_FFFF_FFFF_FFFF + 1"), Ok(i64::MIN as i128));
+        cx.overflow = Overflow::Saturating;
+        assert_eq!(eval(&cx, "-(2^62) * 4"), Ok(i64::MIN as i128));
+        assert_eq!(cx.setvar("x", u64::MAX), Ok(()));
+        assert_eq!(eval(&cx, "x"), Ok(i64::MAX as i128));
+    }
+
+    #[test]
+    fn errors() {
+        let cx = IntContext::new(IntType::I64, Overflow::Wrapping);
+        assert_eq!(eval(&cx, "1 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(eval(&cx, "1.5 + 1"), Err("Not an integer: 1.5".to_string()));
+        assert_eq!(eval(&cx, "2^-1"), Err("Negative exponent".to_string()));
+        assert_eq!(eval(&cx, "y"), Err("Unknown Variable: y".to_string()));
+    }
+
+    #[test]
+    fn series() {
+        let mut cx = IntContext::new(IntType::U64, Overflow::Checked);
+        assert_eq!(eval(&cx, "sum(1 << i, i, 0, 63)"), Ok(u64::MAX as i128));
+        assert_eq!(
+            eval(&cx, "prod(k, k, 1, 20)"),
+            Ok(2_432_902_008_176_640_000)
+        );
+        assert_eq!(
+            eval(&cx, "prod(k, k, 1, 21)"),
+            Err("Integer overflow".to_string())
+        );
+        cx.setvar("n", 4u8).unwrap();
+        assert_eq!(eval(&cx, "sum(n * k, k, n, n + 1)"), Ok(36));
+        assert_eq!(
+            eval(&cx, "integrate(x, x, 0, 1)"),
+            Err("Unknown function: integrate".to_string())
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::grammar::Fixity;
+use crate::parser::RPNExpr;
+use crate::rpnprint::{implicit_product, walk, Notation, Operand, PrintOptions};
+use crate::tokenizer::{MathToken, Radix};
+
+static GREEK: &[&str] = &[
+    "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa",
+    "lambda", "mu", "nu", "xi", "pi", "rho", "sigma", "tau", "upsilon", "phi", "chi", "psi",
+    "omega", "Gamma", "Delta", "Theta", "Lambda", "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi",
+    "Omega",
+];
+
+// functions LaTeX has a command for, eg: \sin
+static FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "sec", "csc", "cot", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "coth", "exp", "log", "ln", "lg", "max", "min", "gcd", "det",
+];
+
+struct Latex;
+
+// greek letters by name, x_1 as a subscript and longer names upright
+fn variable(name: &str) -> String {
+    if let Some((base, sub)) = name
+        .split_once('_')
+        .filter(|(b, s)| !b.is_empty() && !s.is_empty())
+    {
+        return format!("{}_{{{}}}", variable(base), variable(sub));
+    }
+    match name {
+        _ if GREEK.contains(&name) => format!("\\{}", name),
+        _ if name.chars().count() == 1 => name.to_string(),
+        _ => format!("\\mathrm{{{}}}", escape(name)),
+    }
+}
+
+// an underscore that isn't a subscript, eg: in 'x_' or a function's name
+fn escape(name: &str) -> String {
+    name.replace('_', "\\_")
+}
+
+// integers in another radix have it as a subscript, eg: 1F_{16}, there's
+// no notation for other numbers in a radix so they're decimal
+fn integer(n: u64, radix: Radix) -> String {
+    match radix {
+        Radix::Bin => format!("{:b}_{{2}}", n),
+        Radix::Oct => format!("{:o}_{{8}}", n),
+        Radix::Dec => n.to_string(),
+        Radix::Hex => format!("\\mathrm{{{:X}}}_{{16}}", n),
+    }
+}
+
+fn number(x: f64, radix: Radix) -> String {
+    let sign = if x.is_sign_negative() { "-" } else { "" };
+    match radix {
+        Radix::Dec => x.to_string(),
+        _ if x.fract() == 0.0 && x.abs() < 2f64.powi(64) => {
+            format!("{}{}", sign, integer(x.abs() as u64, radix))
+        }
+        _ => x.to_string(),
+    }
+}
+
+impl Notation for Latex {
+    fn leaf(&self, token: &MathToken) -> String {
+        match *token {
+            MathToken::Number(x, radix) => number(x, radix),
+            MathToken::Integer(n, radix) => integer(n, radix),
+            MathToken::Variable(ref x) => variable(x),
+            MathToken::Unit(ref unit) => format!("\\mathrm{{{}}}", unit.name.replace(' ', "\\,")),
+            _ => unreachable!(),
+        }
+    }
+
+    fn group(&self, text: &str) -> String {
+        format!("\\left({}\\right)", text)
+    }
+
+    fn quantity(&self, number: String, unit: String) -> String {
+        format!("{}\\,{}", number, unit)
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Operand) -> String {
+        let op = match op {
+            "~" => "\\lnot ".to_string(),
+            _ if op.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                format!("\\operatorname{{{}}} ", escape(op))
+            }
+            _ => op.to_string(),
+        };
+        match fixity {
+            Fixity::Postfix => format!("{}{}", self.operand(&operand), op.trim_end()),
+            _ => format!("{}{}", op, self.operand(&operand)),
+        }
+    }
+
+    fn binary(&self, op: &str, lhs: Operand, rhs: Operand) -> String {
+        // fractions and exponents group their operands with braces
+        match op {
+            "/" => return format!("\\frac{{{}}}{{{}}}", lhs.text, rhs.text),
+            "^" => return format!("{{{}}}^{{{}}}", self.operand(&lhs), rhs.text),
+            "*" if implicit_product(&lhs, &rhs) => {
+                return format!("{}{}", lhs.text, rhs.text);
+            }
+            _ => (),
+        }
+        let op = match op {
+            "*" => "\\cdot".to_string(),
+            "%" => "\\bmod".to_string(),
+            "==" => "=".to_string(),
+            "!=" => "\\neq".to_string(),
+            "<=" => "\\leq".to_string(),
+            ">=" => "\\geq".to_string(),
+            "&&" => "\\land".to_string(),
+            "&" => "\\mathbin{\\&}".to_string(),
+            "|" => "\\mathbin{|}".to_string(),
+            "<<" => "\\ll".to_string(),
+            ">>" => "\\gg".to_string(),
+            "xor" => "\\oplus".to_string(),
+            _ if op.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                format!("\\operatorname{{{}}}", escape(op))
+            }
+            _ => op.to_string(),
+        };
+        format!("{} {} {}", self.operand(&lhs), op, self.operand(&rhs))
+    }
+
+    fn function(&self, name: &str, args: Vec<String>) -> String {
+        match &args[..] {
+            [arg] if name == "sqrt" => format!("\\sqrt{{{}}}", arg),
+            [arg] if name == "abs" => format!("\\left|{}\\right|", arg),
+            _ if FUNCTIONS.contains(&name) => format!("\\{}{}", name, self.group(&args.join(", "))),
+            _ => {
+                let args = self.group(&args.join(", "));
+                format!("\\operatorname{{{}}}{}", escape(name), args)
+            }
+        }
+    }
+}
+
+impl RPNExpr {
+    // render as a LaTeX formula, eg: for reports
+    pub fn to_latex(&self) -> Result<String, String> {
+        self.to_latex_with(&PrintOptions::default())
+    }
+
+    // with the operators in 'opts' telling where parens go
+    pub fn to_latex_with(&self, opts: &PrintOptions) -> Result<String, String> {
+        Ok(walk(&Latex, &self.build_ast()?, opts).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{Assoc, Grammar, Operator};
+    use crate::parser::ShuntingParser;
+    use crate::rpnprint::PrintOptions;
+
+    fn latex(expr: &str) -> String {
+        ShuntingParser::parse_str(expr).unwrap().to_latex().unwrap()
+    }
+
+    #[test]
+    fn latex_ops() {
+        assert_eq!(latex("(a+1)/(b-2)"), "\\frac{a + 1}{b - 2}");
+        assert_eq!(
+            latex("x^(n+1) + (x+1)^2"),
+            "{x}^{n + 1} + {\\left(x + 1\\right)}^{2}"
+        );
+        assert_eq!(latex("2*x - 3*x^2 + 2*3"), "2x - 3{x}^{2} + 2 \\cdot 3");
+        assert_eq!(latex("a*b*(c+d)"), "a \\cdot b \\cdot \\left(c + d\\right)");
+        assert_eq!(
+            latex("-(a/b) * 7!"),
+            "-\\left(\\frac{a}{b}\\right) \\cdot 7!"
+        );
+        assert_eq!(latex("a <= b"), "a \\leq b");
+    }
+
+    #[test]
+    fn latex_names() {
+        assert_eq!(latex("sqrt(abs(x))"), "\\sqrt{\\left|x\\right|}");
+        assert_eq!(
+            latex("2*sin(theta) * f(x, y)"),
+            "2\\sin\\left(\\theta\\right) \\cdot \\operatorname{f}\\left(x, y\\right)"
+        );
+        assert_eq!(
+            latex("2*pi*r_1 + Omega + rate"),
+            "2\\pi \\cdot r_{1} + \\Omega + \\mathrm{rate}"
+        );
+        assert_eq!(
+            latex("max_rate_(x_, _y)"),
+            "\\operatorname{max\\_rate\\_}\\left(\\mathrm{x\\_}, \\mathrm{\\_y}\\right)"
+        );
+    }
+
+    #[test]
+    fn latex_numbers() {
+        assert_eq!(
+            latex("0x1F + 0b101 * 0o17"),
+            "\\mathrm{1F}_{16} + 101_{2} \\cdot 17_{8}"
+        );
+        assert_eq!(latex("0x1.8p0 - 1.5e-3"), "1.5 - 0.0015");
+        let grammar = Grammar::integer();
+        let expr = ShuntingParser::parse_str_with("~a xor 0xFF", &grammar).unwrap();
+        assert_eq!(
+            expr.to_latex().unwrap(),
+            "\\lnot a \\oplus \\mathrm{FF}_{16}"
+        );
+        let mut grammar = Grammar::default();
+        grammar.operators.add(Operator::prefix("not", 20));
+        let expr = ShuntingParser::parse_str_with("not x_", &grammar).unwrap();
+        assert_eq!(
+            expr.to_latex().unwrap(),
+            "\\operatorname{not} \\mathrm{x\\_}"
+        );
+
+        // operators the options don't know are grouped
+        grammar
+            .operators
+            .add(Operator::infix("<+>", 25, Assoc::Left));
+        let opts = PrintOptions {
+            operators: grammar.operators.clone(),
+            ..PrintOptions::default()
+        };
+        let expr = ShuntingParser::parse_str_with("1 <+> 2 <+> 3", &grammar).unwrap();
+        assert_eq!(expr.to_latex().unwrap(), "\\left(1 <+> 2\\right) <+> 3");
+        assert_eq!(expr.to_latex_with(&opts).unwrap(), "1 <+> 2 <+> 3");
+    }
+}
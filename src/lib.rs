@@ -1,8 +1,26 @@
+mod codegen;
+mod derivative;
+mod grammar;
+mod inspect;
+mod inteval;
+mod latex;
+mod mathml;
 mod parser;
 mod rpneval;
 mod rpnprint;
+mod rpntext;
 mod scanner;
+mod sheet;
+mod solve;
 mod tokenizer;
+mod units;
 
-pub use crate::parser::{RPNExpr, ShuntingParser};
+pub use crate::grammar::{Assoc, Fixity, Grammar, Operator, OperatorTable};
+pub use crate::inteval::{IntContext, IntType, Overflow};
+pub use crate::parser::{ExprTree, FunctionDef, RPNExpr, ShuntingParser, SpannedExpr};
 pub use crate::rpneval::MathContext;
+pub use crate::rpnprint::{Parens, PrintOptions};
+pub use crate::sheet::Sheet;
+pub use crate::solve::{Derivative, Method, SolveError, Start};
+pub use crate::tokenizer::{MathToken, MathTokenizer, Radix};
+pub use crate::units::{Dimension, Quantity, Unit, UnitError, Units};
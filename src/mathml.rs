@@ -0,0 +1,240 @@
+use crate::grammar::Fixity;
+use crate::parser::RPNExpr;
+use crate::rpnprint::{implicit_product, walk, Notation, Operand, PrintOptions};
+use crate::tokenizer::{MathToken, Radix};
+
+static GREEK: &[(&str, char)] = &[
+    ("alpha", 'α'),
+    ("beta", 'β'),
+    ("gamma", 'γ'),
+    ("delta", 'δ'),
+    ("epsilon", 'ε'),
+    ("zeta", 'ζ'),
+    ("eta", 'η'),
+    ("theta", 'θ'),
+    ("iota", 'ι'),
+    ("kappa", 'κ'),
+    ("lambda", 'λ'),
+    ("mu", 'μ'),
+    ("nu", 'ν'),
+    ("xi", 'ξ'),
+    ("pi", 'π'),
+    ("rho", 'ρ'),
+    ("sigma", 'σ'),
+    ("tau", 'τ'),
+    ("upsilon", 'υ'),
+    ("phi", 'φ'),
+    ("chi", 'χ'),
+    ("psi", 'ψ'),
+    ("omega", 'ω'),
+    ("Gamma", 'Γ'),
+    ("Delta", 'Δ'),
+    ("Theta", 'Θ'),
+    ("Lambda", 'Λ'),
+    ("Xi", 'Ξ'),
+    ("Pi", 'Π'),
+    ("Sigma", 'Σ'),
+    ("Upsilon", 'Υ'),
+    ("Phi", 'Φ'),
+    ("Psi", 'Ψ'),
+    ("Omega", 'Ω'),
+];
+
+struct MathMl;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn mo(op: &str) -> String {
+    format!("<mo>{}</mo>", escape(op))
+}
+
+fn mrow(items: &[String]) -> String {
+    format!("<mrow>{}</mrow>", items.concat())
+}
+
+// digits in a radix other than 10 get it as a subscript, as in LaTeX
+fn integer(sign: &str, n: u64, radix: Radix) -> String {
+    let (digits, base) = match radix {
+        Radix::Bin => (format!("{:b}", n), 2),
+        Radix::Oct => (format!("{:o}", n), 8),
+        Radix::Dec => return format!("<mn>{}{}</mn>", sign, n),
+        Radix::Hex => (format!("{:X}", n), 16),
+    };
+    format!("<msub><mn>{}{}</mn><mn>{}</mn></msub>", sign, digits, base)
+}
+
+// hex floats that aren't integers print in decimal
+fn number(x: f64, radix: Radix) -> String {
+    let sign = if x.is_sign_negative() { "-" } else { "" };
+    match radix {
+        Radix::Dec => format!("<mn>{}</mn>", x),
+        _ if x.fract() == 0.0 && x.abs() < 2f64.powi(64) => integer(sign, x.abs() as u64, radix),
+        _ => format!("<mn>{}</mn>", x),
+    }
+}
+
+impl Notation for MathMl {
+    fn leaf(&self, token: &MathToken) -> String {
+        match *token {
+            MathToken::Number(x, radix) => number(x, radix),
+            MathToken::Integer(n, radix) => integer("", n, radix),
+            MathToken::Variable(ref x) => match GREEK.iter().find(|g| g.0 == x) {
+                Some((_, letter)) => format!("<mi>{}</mi>", letter),
+                None => format!("<mi>{}</mi>", escape(x)),
+            },
+            MathToken::Unit(ref unit) => {
+                format!("<mi mathvariant=\"normal\">{}</mi>", escape(&unit.name))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn group(&self, text: &str) -> String {
+        mrow(&[mo("("), text.to_string(), mo(")")])
+    }
+
+    fn quantity(&self, number: String, unit: String) -> String {
+        mrow(&[number, unit])
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Operand) -> String {
+        match fixity {
+            Fixity::Postfix => mrow(&[self.operand(&operand), mo(op)]),
+            _ => mrow(&[mo(op), self.operand(&operand)]),
+        }
+    }
+
+    fn binary(&self, op: &str, lhs: Operand, rhs: Operand) -> String {
+        // fractions and exponents lay out their operands instead of parens
+        match op {
+            "/" => return format!("<mfrac>{}{}</mfrac>", lhs.text, rhs.text),
+            "^" => return format!("<msup>{}{}</msup>", self.operand(&lhs), rhs.text),
+            "*" if implicit_product(&lhs, &rhs) => {
+                return mrow(&[lhs.text, mo("\u{2062}"), rhs.text]); // invisible times
+            }
+            _ => (),
+        }
+        let op = match op {
+            "*" => "\u{22C5}", // dot operator
+            "==" => "=",
+            "!=" => "≠",
+            "<=" => "≤",
+            ">=" => "≥",
+            "&&" => "∧",
+            "xor" => "⊕",
+            _ => op,
+        };
+        mrow(&[self.operand(&lhs), mo(op), self.operand(&rhs)])
+    }
+
+    fn function(&self, name: &str, args: Vec<String>) -> String {
+        match &args[..] {
+            [arg] if name == "sqrt" => format!("<msqrt>{}</msqrt>", arg),
+            [arg] if name == "abs" => mrow(&[mo("|"), arg.to_string(), mo("|")]),
+            _ => {
+                let args = args.join(&mo(","));
+                let name = format!("<mi>{}</mi>", escape(name));
+                mrow(&[name, mo("\u{2061}"), self.group(&args)]) // function application
+            }
+        }
+    }
+}
+
+impl RPNExpr {
+    // render as a MathML <math> element, for browsers to show natively
+    pub fn to_mathml(&self) -> Result<String, String> {
+        self.to_mathml_with(&PrintOptions::default())
+    }
+
+    // with the operators in 'opts' telling where parens go
+    pub fn to_mathml_with(&self, opts: &PrintOptions) -> Result<String, String> {
+        let body = walk(&MathMl, &self.build_ast()?, opts).0;
+        Ok(format!(
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\">{}</math>",
+            body
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::Grammar;
+    use crate::parser::ShuntingParser;
+    use crate::rpnprint::PrintOptions;
+
+    fn mathml(expr: &str) -> String {
+        let expr = ShuntingParser::parse_str(expr)
+            .unwrap()
+            .to_mathml()
+            .unwrap();
+        let prefix = "<math xmlns=\"http://www.w3.org/1998/Math/MathML\">";
+        expr[prefix.len()..expr.len() - "</math>".len()].to_string()
+    }
+
+    #[test]
+    fn mathml_ops() {
+        assert_eq!(
+            mathml("(a+1)/2"),
+            "<mfrac><mrow><mi>a</mi><mo>+</mo><mn>1</mn></mrow><mn>2</mn></mfrac>"
+        );
+        assert_eq!(
+            mathml("(x+1)^2"),
+            "<msup><mrow><mo>(</mo><mrow><mi>x</mi><mo>+</mo><mn>1</mn></mrow><mo>)</mo></mrow><mn>2</mn></msup>"
+        );
+        assert_eq!(
+            mathml("2*x <= a*b"),
+            "<mrow><mrow><mn>2</mn><mo>\u{2062}</mo><mi>x</mi></mrow><mo>≤</mo><mrow><mi>a</mi><mo>\u{22C5}</mo><mi>b</mi></mrow></mrow>"
+        );
+        assert_eq!(
+            mathml("-x!"),
+            "<mrow><mo>-</mo><mrow><mi>x</mi><mo>!</mo></mrow></mrow>"
+        );
+        assert_eq!(
+            mathml("a < b"),
+            "<mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow>"
+        );
+
+        // operators the options don't know are grouped
+        use crate::grammar::{Assoc, Operator};
+        let mut grammar = Grammar::default();
+        grammar
+            .operators
+            .add(Operator::infix("<+>", 25, Assoc::Left));
+        let opts = PrintOptions {
+            operators: grammar.operators.clone(),
+            ..PrintOptions::default()
+        };
+        let expr = ShuntingParser::parse_str_with("(1 <+> 2) * 3", &grammar).unwrap();
+        let grouped = "<mrow><mrow><mo>(</mo><mrow><mn>1</mn><mo>&lt;+&gt;</mo><mn>2</mn></mrow><mo>)</mo></mrow><mo>\u{22C5}</mo><mn>3</mn></mrow>";
+        assert!(expr.to_mathml().unwrap().contains(grouped));
+        assert!(expr.to_mathml_with(&opts).unwrap().contains(grouped));
+    }
+
+    #[test]
+    fn mathml_numbers() {
+        let hex = "<msub><mn>1F</mn><mn>16</mn></msub>";
+        assert_eq!(mathml("0x1F"), hex);
+        assert_eq!(mathml("0b101 + 0o17"), "<mrow><msub><mn>101</mn><mn>2</mn></msub><mo>+</mo><msub><mn>17</mn><mn>8</mn></msub></mrow>");
+        assert_eq!(mathml("0x1.8p0"), "<mn>1.5</mn>");
+        // integers read exactly print the same way
+        let grammar = Grammar::integer();
+        let expr = ShuntingParser::parse_str_with("0x1F", &grammar).unwrap();
+        assert!(expr.to_mathml().unwrap().contains(hex));
+    }
+
+    #[test]
+    fn mathml_functions() {
+        assert_eq!(
+            mathml("sqrt(abs(theta))"),
+            "<msqrt><mrow><mo>|</mo><mi>θ</mi><mo>|</mo></mrow></msqrt>"
+        );
+        assert_eq!(
+            mathml("max(1, y)"),
+            "<mrow><mi>max</mi><mo>\u{2061}</mo><mrow><mo>(</mo><mn>1</mn><mo>,</mo><mi>y</mi><mo>)</mo></mrow></mrow>"
+        );
+    }
+}
@@ -1,40 +1,222 @@
-use crate::tokenizer::{MathToken, MathTokenizer};
+use crate::grammar::{Assoc, Fixity, Grammar};
+use crate::rpnprint::AST;
+use crate::tokenizer::{is_juxtaposition, MathToken, MathTokenizer};
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ops::Range;
 
-#[derive(PartialEq, Debug)]
-pub enum Assoc {
-    Left,
-    Right,
-    None,
-}
-
-pub fn precedence(mt: &MathToken) -> (usize, Assoc) {
-    // You can play with the relation between exponentiation an unary - by
-    // a. switching order in which the lexer tokenizes, if it tries
-    // operators first then '-' will never be the negative part of number,
-    // else if numbers are tried before operators, - can only be unary
-    // for non-numeric tokens (eg: -(3)).
-    // b. changing the precedence of '-' respect to '^'
-    // If '-' has lower precedence then 2^-3 will fail to evaluate if the
-    // '-' isn't part of the number because ^ will only find 1 operator
-    match *mt {
-        MathToken::OParen => (1, Assoc::Left), // keep at bottom
-        MathToken::BOp(ref o) if o == "+" => (2, Assoc::Left),
-        MathToken::BOp(ref o) if o == "-" => (2, Assoc::Left),
-        MathToken::BOp(ref o) if o == "*" => (3, Assoc::Left),
-        MathToken::BOp(ref o) if o == "/" => (3, Assoc::Left),
-        MathToken::BOp(ref o) if o == "%" => (3, Assoc::Left),
-        MathToken::UOp(ref o) if o == "-" => (5, Assoc::Right), // unary minus
-        MathToken::BOp(ref o) if o == "^" => (5, Assoc::Right),
-        MathToken::UOp(ref o) if o == "!" => (6, Assoc::Left), // factorial
-        MathToken::Function(_, _) => (7, Assoc::Left),
-        _ => (99, Assoc::None),
+// Functions whose arguments are evaluated on demand rather than up front
+pub fn is_lazy(fname: &str) -> bool {
+    matches!(fname, "if" | "piecewise") || binds_variable(fname)
+}
+
+// Special forms like 'sum(i^2, i, 1, n)' whose second argument names a
+// variable bound while evaluating the first
+pub fn binds_variable(fname: &str) -> bool {
+    matches!(fname, "sum" | "prod" | "integrate")
+}
+
+// Mark the variable tokens bound by special forms, the index argument itself
+// and its uses in the first argument
+pub(crate) fn bound_variables(rpn: &[MathToken]) -> Vec<bool> {
+    let mut bound = vec![false; rpn.len()];
+    for (at, token) in rpn.iter().enumerate() {
+        if let MathToken::Quote(n) = *token {
+            if let Some(var) = binding(rpn, at + 1 + n) {
+                let body = at + 1..at + 1 + n;
+                for (token, bound) in rpn[body.clone()].iter().zip(&mut bound[body]) {
+                    *bound |= matches!(*token, MathToken::Variable(ref name) if name == var);
+                }
+                bound[at + 2 + n] = true;
+            }
+        }
+    }
+    bound
+}
+
+// the variable a special form binds if its index argument is quoted at
+// 'at', as in 'Quote(1) Variable(i) Quote(..) a.. Quote(..) b.. sum/4'
+fn binding(rpn: &[MathToken], at: usize) -> Option<&str> {
+    let var = match (rpn.get(at)?, rpn.get(at + 1)?) {
+        (MathToken::Quote(1), MathToken::Variable(var)) => var,
+        _ => return None,
+    };
+    let mut end = at + 2;
+    for _ in 0..2 {
+        match *rpn.get(end)? {
+            MathToken::Quote(n) => end += 1 + n,
+            _ => return None,
+        }
+    }
+    match rpn.get(end)? {
+        MathToken::Function(fname, 4) if binds_variable(fname) => Some(var),
+        _ => None,
     }
 }
 
+// Prefix each argument of lazy functions with a Quote so the evaluator
+// can skip over it and only evaluate the arguments it needs
+pub(crate) fn quote_lazy_args(rpn: Vec<MathToken>) -> Vec<MathToken> {
+    let mut out = Vec::new();
+    let mut starts = Vec::new(); // where each complete subtree begins in 'out'
+    for token in rpn {
+        let operands = starts.len().saturating_sub(token.arity());
+        let args = starts.split_off(operands);
+        let first = *args.first().unwrap_or(&out.len());
+        if matches!(token, MathToken::Function(ref f, n) if is_lazy(f) && n == args.len()) {
+            let mut tail = out.split_off(first);
+            let mut quoted = Vec::new();
+            for &start in args.iter().rev() {
+                quoted.push(tail.split_off(start - first));
+            }
+            for arg in quoted.into_iter().rev() {
+                out.push(MathToken::Quote(arg.len()));
+                out.extend(arg);
+            }
+        }
+        starts.push(first);
+        out.push(token);
+    }
+    out
+}
+
+// Serializes as the flat list of tokens in rpn order
 #[derive(PartialEq, Debug, Clone)]
 pub struct RPNExpr(pub Vec<MathToken>);
 
+// quotes are left out, they're only there for the evaluators and come back
+// on deserializing, which also checks the tokens make a tree
+#[cfg(feature = "serde")]
+impl Serialize for RPNExpr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tokens = self
+            .0
+            .iter()
+            .filter(|token| !matches!(token, MathToken::Quote(_)));
+        serializer.collect_seq(tokens.collect::<Vec<_>>())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for RPNExpr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tokens = Vec::<MathToken>::deserialize(deserializer)?;
+        if let Some(quote) = tokens.iter().find(|t| matches!(t, MathToken::Quote(_))) {
+            return Err(de::Error::custom(format!("Bad token: {:?}", quote)));
+        }
+        let rpn = RPNExpr(quote_lazy_args(tokens));
+        // an empty expression parses, so it may come back too
+        if !rpn.0.is_empty() {
+            rpn.build_ast().map_err(de::Error::custom)?;
+        }
+        Ok(rpn)
+    }
+}
+
+// A function written in expression syntax, eg: f(x, y) = x^2 + y^2
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: RPNExpr,
+}
+
+// An expression along with the char offsets in the source of the subtree
+// rooted at each of its tokens, including the parens around it
+#[derive(PartialEq, Debug, Clone)]
+pub struct SpannedExpr {
+    pub expr: RPNExpr,
+    pub source: String,
+    pub spans: Vec<Range<usize>>,
+}
+
+impl SpannedExpr {
+    // the source of the subtree rooted at the i-th token
+    pub fn text(&self, i: usize) -> String {
+        let span = &self.spans[i];
+        self.source
+            .chars()
+            .skip(span.start)
+            .take(span.len())
+            .collect()
+    }
+}
+
+// An expression as operators and functions over their operands, the nested
+// counterpart of the flat RPNExpr, leaves serialize without 'args'
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExprTree {
+    pub token: MathToken,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub args: Vec<ExprTree>,
+}
+
+impl ExprTree {
+    fn from_ast(ast: &AST) -> Self {
+        match ast {
+            AST::Leaf(token) => ExprTree {
+                token: (*token).clone(),
+                args: Vec::new(),
+            },
+            AST::Node(token, args) => ExprTree {
+                token: (*token).clone(),
+                args: args.iter().map(ExprTree::from_ast).collect(),
+            },
+        }
+    }
+
+    pub fn to_rpn(&self) -> RPNExpr {
+        fn flatten(tree: &ExprTree, out: &mut Vec<MathToken>) {
+            for arg in tree.args.iter() {
+                flatten(arg, out);
+            }
+            out.push(tree.token.clone());
+        }
+        let mut out = Vec::new();
+        flatten(self, &mut out);
+        RPNExpr(quote_lazy_args(out))
+    }
+}
+
+impl RPNExpr {
+    pub fn to_tree(&self) -> Result<ExprTree, String> {
+        Ok(ExprTree::from_ast(&self.build_ast()?))
+    }
+
+    // names of the variables the expression refers to, leaving out those
+    // bound by special forms like the 'i' in 'sum(i^2, i, 1, n)'
+    pub fn variables(&self) -> BTreeSet<String> {
+        let mut vars = BTreeSet::new();
+        for (token, bound) in self.0.iter().zip(bound_variables(&self.0)) {
+            match *token {
+                MathToken::Variable(ref name) if !bound => {
+                    vars.insert(name.clone());
+                }
+                _ => (),
+            }
+        }
+        vars
+    }
+
+    // names of the functions called, along with the number of arguments
+    pub fn functions(&self) -> BTreeSet<(String, usize)> {
+        let mut funcs = BTreeSet::new();
+        for token in self.0.iter() {
+            if let MathToken::Function(ref name, arity) = *token {
+                funcs.insert((name.clone(), arity));
+            }
+        }
+        funcs
+    }
+}
+
 pub struct ShuntingParser;
 
 impl ShuntingParser {
@@ -42,91 +224,340 @@ impl ShuntingParser {
         Self::parse(&mut MathTokenizer::new(expr.chars()))
     }
 
+    pub fn parse_str_with(expr: &str, grammar: &Grammar) -> Result<RPNExpr, String> {
+        let mut lex = MathTokenizer::with_grammar(expr.chars(), grammar.clone());
+        Self::parse_with(&mut lex, grammar)
+    }
+
+    // parse definitions like 'name(p1, p2, ...) = body'
+    pub fn parse_definition(def: &str) -> Result<FunctionDef, String> {
+        Self::parse_definition_with(def, &Grammar::default())
+    }
+
+    pub fn parse_definition_with(def: &str, grammar: &Grammar) -> Result<FunctionDef, String> {
+        let mut lex = MathTokenizer::with_grammar(def.chars(), grammar.clone());
+        let name = match lex.next() {
+            Some(MathToken::Function(name, _)) => name,
+            _ => return Err("Expected function name".to_string()),
+        };
+        lex.next(); // the tokenizer only makes functions out of 'name('
+        let mut params = Vec::new();
+        loop {
+            match lex.next() {
+                Some(MathToken::Variable(param)) if !params.contains(&param) => params.push(param),
+                Some(MathToken::Variable(param)) => {
+                    return Err(format!("Duplicate parameter: {}", param))
+                }
+                Some(MathToken::CParen) if params.is_empty() => break,
+                _ => return Err("Expected parameter name".to_string()),
+            }
+            match lex.next() {
+                Some(MathToken::Comma) => (),
+                Some(MathToken::CParen) => break,
+                _ => return Err("Missing Closing Paren".to_string()),
+            }
+        }
+        if lex.next() != Some(MathToken::BOp("=".to_string())) {
+            return Err("Expected '=' after function header".to_string());
+        }
+        let body = Self::parse_with(&mut lex, grammar)?;
+        if body.0.is_empty() {
+            return Err("Empty function body".to_string());
+        }
+        Ok(FunctionDef { name, params, body })
+    }
+
+    // parse equations like 'lhs = rhs' as 'lhs - rhs', whose roots are the
+    // solutions, anything without '=' is returned as it is
+    pub fn parse_equation(eq: &str) -> Result<RPNExpr, String> {
+        Self::parse_equation_with(eq, &Grammar::default())
+    }
+
+    pub fn parse_equation_with(eq: &str, grammar: &Grammar) -> Result<RPNExpr, String> {
+        let tokens: Vec<_> = MathTokenizer::with_grammar(eq.chars(), grammar.clone()).collect();
+        let mut depth = 0;
+        let mut equals = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match *token {
+                MathToken::OParen => depth += 1,
+                MathToken::CParen => depth -= 1,
+                MathToken::BOp(ref op) if op == "=" && depth == 0 => equals.push(i),
+                _ => (),
+            }
+        }
+        let (lhs, rhs) = match equals[..] {
+            [] => return Self::parse_with(&mut tokens.into_iter(), grammar),
+            [at] => (&tokens[..at], &tokens[at + 1..]),
+            _ => return Err("More than one '=' in equation".to_string()),
+        };
+        let lhs = Self::parse_with(&mut lhs.iter().cloned(), grammar)?;
+        let rhs = Self::parse_with(&mut rhs.iter().cloned(), grammar)?;
+        if lhs.0.is_empty() || rhs.0.is_empty() {
+            return Err("Empty side in equation".to_string());
+        }
+        let mut rpn = lhs.0;
+        rpn.extend(rhs.0);
+        rpn.push(MathToken::BOp("-".to_string()));
+        Ok(RPNExpr(rpn))
+    }
+
     pub fn parse(lex: &mut impl Iterator<Item = MathToken>) -> Result<RPNExpr, String> {
-        let mut out = Vec::new();
+        Self::parse_with(lex, &Grammar::default())
+    }
+
+    // parse tokens binding operators as listed in the grammar's table
+    pub fn parse_with(
+        lex: &mut impl Iterator<Item = MathToken>,
+        grammar: &Grammar,
+    ) -> Result<RPNExpr, String> {
+        let out = Self::parse_spans(lex.map(|token| (token, 0..0)), grammar);
+        Ok(RPNExpr(quote_lazy_args(
+            out.map_err(|(error, _)| error)?.tokens,
+        )))
+    }
+
+    // parse keeping track of where each subtree came from in 'expr'
+    pub fn parse_str_spanned(expr: &str, grammar: &Grammar) -> Result<SpannedExpr, String> {
+        Self::parse_str_located(expr, grammar).map_err(|(error, _)| error)
+    }
+
+    // like parse_str_spanned, errors also tell where in 'expr' they were
+    // found, eg: the paren left open in '(1 + 2'
+    pub fn parse_str_located(
+        expr: &str,
+        grammar: &Grammar,
+    ) -> Result<SpannedExpr, (String, Range<usize>)> {
+        let mut lex = MathTokenizer::with_grammar(expr.chars(), grammar.clone());
+        let tokens = std::iter::from_fn(|| lex.next().map(|token| (token, lex.span())));
+        let out = Self::parse_spans(tokens, grammar)?;
+        let rpn = quote_lazy_args(out.tokens);
+        // quotes take the span of the argument they precede
+        let mut spans = out.spans.into_iter();
+        let mut spans: Vec<_> = rpn
+            .iter()
+            .map(|token| match token {
+                MathToken::Quote(_) => 0..0,
+                _ => spans.next().unwrap(),
+            })
+            .collect();
+        for (i, token) in rpn.iter().enumerate() {
+            if let MathToken::Quote(n) = *token {
+                spans[i] = spans[i + n].clone();
+            }
+        }
+        Ok(SpannedExpr {
+            expr: RPNExpr(rpn),
+            source: expr.to_string(),
+            spans,
+        })
+    }
+
+    fn parse_spans(
+        lex: impl Iterator<Item = (MathToken, Range<usize>)>,
+        grammar: &Grammar,
+    ) -> Result<Output, (String, Range<usize>)> {
+        let mut out = Output::default();
         let mut stack = Vec::new();
         let mut arity = Vec::<usize>::new();
+        let mut prev = None;
 
-        for token in lex {
+        for (token, span) in lex {
+            if is_juxtaposition(&prev, &token) {
+                return Err(("Missing operator between operands".to_string(), span));
+            }
+            let after_oparen = prev == Some(MathToken::OParen);
+            prev = Some(token.clone());
             match token {
-                MathToken::Number(_) => out.push(token),
-                MathToken::Variable(_) => out.push(token),
-                MathToken::OParen => stack.push(token),
+                MathToken::Number(..) | MathToken::Integer(..) => out.push(token, span),
+                MathToken::Variable(_) | MathToken::Unit(_) => out.push(token, span),
+                MathToken::OParen => stack.push((token, span)),
                 MathToken::Function(_, _) => {
-                    stack.push(token);
+                    stack.push((token, span));
                     arity.push(1);
                 }
                 MathToken::Comma | MathToken::CParen => {
-                    while !stack.is_empty() && stack.last() != Some(&MathToken::OParen) {
-                        out.push(stack.pop().unwrap());
+                    while !stack.is_empty() && stack.last().unwrap().0 != MathToken::OParen {
+                        let (top, top_span) = stack.pop().unwrap();
+                        out.push(top, top_span);
                     }
                     if stack.is_empty() {
-                        return Err("Missing Opening Paren".to_string());
+                        return Err(("Missing Opening Paren".to_string(), span));
                     }
                     // end of grouping: check if this is a function call
                     if token == MathToken::CParen {
-                        stack.pop(); // peel matching OParen
+                        let (_, oparen) = stack.pop().unwrap(); // peel matching OParen
                         match stack.pop() {
-                            Some(MathToken::Function(func, _)) => {
-                                out.push(MathToken::Function(func, arity.pop().unwrap()))
+                            // an empty argument list like 'f()' takes no arguments
+                            Some((MathToken::Function(func, _), name)) if after_oparen => {
+                                arity.pop();
+                                out.push(MathToken::Function(func, 0), name.start..span.end)
+                            }
+                            Some((MathToken::Function(func, _), name)) => {
+                                let func = MathToken::Function(func, arity.pop().unwrap());
+                                out.push(func, name.start..span.end)
+                            }
+                            other => {
+                                stack.extend(other);
+                                // the parens belong to the subtree they enclose
+                                if let (false, Some(last)) = (after_oparen, out.spans.last_mut()) {
+                                    *last = oparen.start..span.end;
+                                }
                             }
-                            Some(other) => stack.push(other),
-                            None => (),
                         }
                     } else if let Some(a) = arity.last_mut() {
                         *a += 1;
                     } // Comma
                 }
-                MathToken::UOp(_) | MathToken::BOp(_) => {
-                    let (prec_rhs, assoc_rhs) = precedence(&token);
-                    while !stack.is_empty() {
-                        let (prec_lhs, _) = precedence(stack.last().unwrap());
-                        match prec_lhs.cmp(&prec_rhs) {
-                            Ordering::Greater => out.push(stack.pop().unwrap()),
-                            Ordering::Less => break,
-                            Ordering::Equal => match assoc_rhs {
-                                Assoc::Left => out.push(stack.pop().unwrap()),
-                                Assoc::None => return Err("No Associativity".to_string()),
-                                Assoc::Right => break,
-                            },
-                        }
-                    }
-                    stack.push(token);
+                MathToken::UOp(..) | MathToken::BOp(_) => {
+                    Self::push_operator(token, span, &mut out, &mut stack, grammar)?
                 }
-                MathToken::Unknown(lexeme) => return Err(format!("Bad token: {}", lexeme)),
+                MathToken::Unknown(lexeme) => return Err((format!("Bad token: {}", lexeme), span)),
+                MathToken::Quote(_) => return Err((format!("Bad token: {:?}", token), span)),
             }
         }
-        while let Some(top) = stack.pop() {
+        while let Some((top, span)) = stack.pop() {
             match top {
-                MathToken::OParen => return Err("Missing Closing Paren".to_string()),
-                token => out.push(token),
+                MathToken::OParen => return Err(("Missing Closing Paren".to_string(), span)),
+                token => out.push(token, span),
             }
         }
-        Ok(RPNExpr(out))
+        Ok(out)
+    }
+
+    // pop the operators binding tighter than 'token' to the output and push
+    // it on the stack, a comparison meeting another one is an error unless
+    // comparisons chain, then 'a < b < c' turns into 'a < b && b < c'
+    fn push_operator(
+        token: MathToken,
+        span: Range<usize>,
+        out: &mut Output,
+        stack: &mut Vec<(MathToken, Range<usize>)>,
+        grammar: &Grammar,
+    ) -> Result<(), (String, Range<usize>)> {
+        let table = &grammar.operators;
+        let (prec_rhs, assoc_rhs) = match table.lookup(&token) {
+            Some(op) => (op.precedence, op.assoc),
+            None => return Err((format!("Unknown operator: {}", symbol(&token)), span)),
+        };
+        while let Some((top, _)) = stack.last() {
+            if *top == MathToken::OParen {
+                break;
+            }
+            let (prec_lhs, _) = table.precedence(top);
+            match prec_lhs.cmp(&prec_rhs) {
+                Ordering::Greater => out.pop_from(stack),
+                Ordering::Less => break,
+                Ordering::Equal => match assoc_rhs {
+                    Assoc::Left => out.pop_from(stack),
+                    Assoc::Right => break,
+                    Assoc::None if grammar.chained_comparisons && is_chain(top, &token) => {
+                        // the left comparison needs both its operands, as in
+                        // '1 < < 2', before its right one can be copied
+                        if out.starts.len() < 2 {
+                            let error = format!("Missing operands for {}", symbol(top));
+                            return Err((error, span));
+                        }
+                        // the middle operand is the last subtree in the output,
+                        // it's copied rather than bound so it runs twice
+                        let middle = out.starts[out.starts.len() - 1];
+                        let middle = (out.tokens[middle..].to_vec(), out.spans[middle..].to_vec());
+                        out.pop_from(stack);
+                        let and = MathToken::BOp("&&".to_string());
+                        Self::push_operator(and, span.clone(), out, stack, grammar)?;
+                        out.starts.push(out.tokens.len());
+                        out.tokens.extend(middle.0);
+                        out.spans.extend(middle.1);
+                        break;
+                    }
+                    Assoc::None => {
+                        let error = format!(
+                            "Can't chain non-associative operators '{}' and '{}'",
+                            symbol(top),
+                            symbol(&token)
+                        );
+                        return Err((error, span));
+                    }
+                },
+            }
+        }
+        // postfix operators apply to what's already in the output
+        match token {
+            MathToken::UOp(_, Fixity::Postfix) => out.push(token, span),
+            _ => stack.push((token, span)),
+        }
+        Ok(())
     }
 }
 
+// The rpn being built along with the source extent of each subtree
+#[derive(Default)]
+struct Output {
+    tokens: Vec<MathToken>,
+    spans: Vec<Range<usize>>,
+    starts: Vec<usize>, // where each complete subtree begins in 'tokens'
+}
+
+impl Output {
+    // add a token, its extent grows to cover the operands it takes, which
+    // already cover theirs
+    fn push(&mut self, token: MathToken, span: Range<usize>) {
+        let operands = self.starts.len().saturating_sub(token.arity());
+        let args = self.starts.split_off(operands);
+        let roots = args.iter().skip(1).map(|start| start - 1);
+        let roots = roots.chain(args.first().map(|_| self.tokens.len() - 1));
+        let extent = roots
+            .map(|root| &self.spans[root])
+            .fold(span, |ext, s| ext.start.min(s.start)..ext.end.max(s.end));
+        self.starts
+            .push(*args.first().unwrap_or(&self.tokens.len()));
+        self.tokens.push(token);
+        self.spans.push(extent);
+    }
+
+    fn pop_from(&mut self, stack: &mut Vec<(MathToken, Range<usize>)>) {
+        let (token, span) = stack.pop().unwrap();
+        self.push(token, span);
+    }
+}
+
+fn symbol(token: &MathToken) -> &str {
+    match *token {
+        MathToken::UOp(ref op, _) | MathToken::BOp(ref op) => op,
+        _ => "",
+    }
+}
+
+fn is_chain(lhs: &MathToken, rhs: &MathToken) -> bool {
+    matches!((lhs, rhs), (MathToken::BOp(l), MathToken::BOp(r)) if is_comparison(l) && is_comparison(r))
+}
+
+pub fn is_comparison(op: &str) -> bool {
+    matches!(op, "<" | "<=" | ">" | ">=" | "==" | "!=")
+}
+
 #[cfg(test)]
+#[allow(clippy::useless_format)]
 mod tests {
-    use crate::parser::{RPNExpr, ShuntingParser};
-    use crate::tokenizer::MathToken;
+    use crate::grammar::Fixity;
+    use crate::parser::{FunctionDef, RPNExpr, ShuntingParser};
+    use crate::tokenizer::{MathToken, Radix};
 
     #[test]
     fn test_parse1() {
         let rpn = ShuntingParser::parse_str("3+4*2/-(1-5)^2^3").unwrap();
         let expect = vec![
-            MathToken::Number(3.0),
-            MathToken::Number(4.0),
-            MathToken::Number(2.0),
+            MathToken::Number(3.0, Radix::Dec),
+            MathToken::Number(4.0, Radix::Dec),
+            MathToken::Number(2.0, Radix::Dec),
             MathToken::BOp(format!("*")),
-            MathToken::Number(1.0),
-            MathToken::Number(5.0),
+            MathToken::Number(1.0, Radix::Dec),
+            MathToken::Number(5.0, Radix::Dec),
             MathToken::BOp(format!("-")),
-            MathToken::Number(2.0),
-            MathToken::Number(3.0),
+            MathToken::Number(2.0, Radix::Dec),
+            MathToken::Number(3.0, Radix::Dec),
             MathToken::BOp(format!("^")),
             MathToken::BOp(format!("^")),
-            MathToken::UOp(format!("-")),
+            MathToken::UOp(format!("-"), Fixity::Prefix),
             MathToken::BOp(format!("/")),
             MathToken::BOp(format!("+")),
         ];
@@ -136,17 +567,17 @@ mod tests {
     fn test_parse2() {
         let rpn = ShuntingParser::parse_str("3.4e-2 * sin(x)/(7! % -4) * max(2, x)").unwrap();
         let expect = vec![
-            MathToken::Number(3.4e-2),
+            MathToken::Number(3.4e-2, Radix::Dec),
             MathToken::Variable(format!("x")),
             MathToken::Function(format!("sin"), 1),
             MathToken::BOp(format!("*")),
-            MathToken::Number(7.0),
-            MathToken::UOp(format!("!")),
-            MathToken::Number(4.0),
-            MathToken::UOp(format!("-")),
+            MathToken::Number(7.0, Radix::Dec),
+            MathToken::UOp(format!("!"), Fixity::Postfix),
+            MathToken::Number(4.0, Radix::Dec),
+            MathToken::UOp(format!("-"), Fixity::Prefix),
             MathToken::BOp(format!("%")),
             MathToken::BOp(format!("/")),
-            MathToken::Number(2.0),
+            MathToken::Number(2.0, Radix::Dec),
             MathToken::Variable(format!("x")),
             MathToken::Function(format!("max"), 2),
             MathToken::BOp(format!("*")),
@@ -158,15 +589,15 @@ mod tests {
     fn test_parse3() {
         let rpn = ShuntingParser::parse_str("sqrt(-(1-x^2) / (1 + x^2))").unwrap();
         let expect = vec![
-            MathToken::Number(1.0),
+            MathToken::Number(1.0, Radix::Dec),
             MathToken::Variable(format!("x")),
-            MathToken::Number(2.0),
+            MathToken::Number(2.0, Radix::Dec),
             MathToken::BOp(format!("^")),
             MathToken::BOp(format!("-")),
-            MathToken::UOp(format!("-")),
-            MathToken::Number(1.0),
+            MathToken::UOp(format!("-"), Fixity::Prefix),
+            MathToken::Number(1.0, Radix::Dec),
             MathToken::Variable(format!("x")),
-            MathToken::Number(2.0),
+            MathToken::Number(2.0, Radix::Dec),
             MathToken::BOp(format!("^")),
             MathToken::BOp(format!("+")),
             MathToken::BOp(format!("/")),
@@ -184,10 +615,25 @@ mod tests {
         assert_eq!(rpn, Err(format!("Missing Opening Paren")));
 
         let rpn = ShuntingParser::parse_str("max 4, 6, 4)");
+        assert_eq!(rpn, Err(format!("Missing operator between operands")));
+
+        let rpn = ShuntingParser::parse_str("4, 6, 4)");
         assert_eq!(rpn, Err(format!("Missing Opening Paren")));
+
+        use crate::grammar::Grammar;
+        let chained = Grammar {
+            chained_comparisons: true,
+            ..Grammar::default()
+        };
+        let rpn = ShuntingParser::parse_str_with("< < 1", &chained);
+        assert_eq!(rpn, Err(format!("Missing operands for <")));
+
+        let rpn = ShuntingParser::parse_str_with("1 < < 2", &chained);
+        assert_eq!(rpn, Err(format!("Missing operands for <")));
     }
 
     #[test]
+    #[allow(clippy::single_match)]
     fn check_arity() {
         use std::collections::HashMap;
         let rpn = ShuntingParser::parse_str("sin(1)+(max(2, gamma(3.5), gcd(24, 8))+sum(i,0,10))")
@@ -209,4 +655,301 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn implicit_multiplication() {
+        use crate::grammar::Grammar;
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            ..Grammar::default()
+        };
+        let rpn = ShuntingParser::parse_str_with("2x^2 (a+b)(a-b)", &grammar).unwrap();
+        let expect = ShuntingParser::parse_str("2*x^2*(a+b)*(a-b)").unwrap();
+        assert_eq!(rpn, expect);
+
+        let rpn = ShuntingParser::parse_str("2x");
+        assert_eq!(rpn, Err("Missing operator between operands".to_string()));
+        let rpn = ShuntingParser::parse_str("(a+b)(a-b)");
+        assert_eq!(rpn, Err("Missing operator between operands".to_string()));
+    }
+
+    #[test]
+    fn empty_call() {
+        let rpn = ShuntingParser::parse_str("f() + g(1)").unwrap();
+        let expect = vec![
+            MathToken::Function("f".to_string(), 0),
+            MathToken::Number(1.0, Radix::Dec),
+            MathToken::Function("g".to_string(), 1),
+            MathToken::BOp("+".to_string()),
+        ];
+        assert_eq!(rpn, RPNExpr(expect));
+    }
+
+    #[test]
+    fn parse_definition() {
+        let def = ShuntingParser::parse_definition("f(x, y) = x^2 + y^2").unwrap();
+        let expect = FunctionDef {
+            name: "f".to_string(),
+            params: vec!["x".to_string(), "y".to_string()],
+            body: ShuntingParser::parse_str("x^2 + y^2").unwrap(),
+        };
+        assert_eq!(def, expect);
+
+        let def = ShuntingParser::parse_definition("two() = 2").unwrap();
+        assert!(def.params.is_empty());
+    }
+
+    #[test]
+    fn bad_definition() {
+        let def = ShuntingParser::parse_definition("x + 1 = 2");
+        assert_eq!(def, Err("Expected function name".to_string()));
+
+        let def = ShuntingParser::parse_definition("f(x, 2) = x");
+        assert_eq!(def, Err("Expected parameter name".to_string()));
+
+        let def = ShuntingParser::parse_definition("f(x, x) = x");
+        assert_eq!(def, Err("Duplicate parameter: x".to_string()));
+
+        let def = ShuntingParser::parse_definition("f(x) + x");
+        assert_eq!(def, Err("Expected '=' after function header".to_string()));
+
+        let def = ShuntingParser::parse_definition("f(x) = ");
+        assert_eq!(def, Err("Empty function body".to_string()));
+    }
+
+    #[test]
+    fn quote_lazy_args() {
+        let rpn = ShuntingParser::parse_str("if(x < 1, 2, if(y, 3, 4) * 5)").unwrap();
+        let expect = vec![
+            MathToken::Quote(3),
+            MathToken::Variable("x".to_string()),
+            MathToken::Number(1.0, Radix::Dec),
+            MathToken::BOp("<".to_string()),
+            MathToken::Quote(1),
+            MathToken::Number(2.0, Radix::Dec),
+            MathToken::Quote(9),
+            MathToken::Quote(1),
+            MathToken::Variable("y".to_string()),
+            MathToken::Quote(1),
+            MathToken::Number(3.0, Radix::Dec),
+            MathToken::Quote(1),
+            MathToken::Number(4.0, Radix::Dec),
+            MathToken::Function("if".to_string(), 3),
+            MathToken::Number(5.0, Radix::Dec),
+            MathToken::BOp("*".to_string()),
+            MathToken::Function("if".to_string(), 3),
+        ];
+        assert_eq!(rpn, RPNExpr(expect));
+    }
+
+    #[test]
+    fn custom_operators() {
+        use crate::grammar::{Assoc, Grammar, Operator};
+        let mut grammar = Grammar::default();
+        grammar.operators.add(Operator::infix("⊕", 25, Assoc::Left));
+        grammar.operators.add(Operator::infix("^", 55, Assoc::Left));
+        let rpn = ShuntingParser::parse_str_with("1 ⊕ 2 * 3 ⊕ 2^3^2", &grammar).unwrap();
+        let expect = ShuntingParser::parse_str("(1 + 2 * 3) + (2^3)^2").unwrap();
+        let rename = |token: &MathToken| match token {
+            MathToken::BOp(op) if op == "⊕" => MathToken::BOp("+".to_string()),
+            _ => token.clone(),
+        };
+        assert_eq!(rpn.0.iter().map(rename).collect::<Vec<_>>(), expect.0);
+
+        assert_eq!(
+            ShuntingParser::parse_str("a = b"),
+            Err("Unknown operator: =".to_string())
+        );
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        use crate::grammar::Grammar;
+        assert_eq!(
+            ShuntingParser::parse_str("a == b == c"),
+            Err("Can't chain non-associative operators '==' and '=='".to_string())
+        );
+        assert_eq!(
+            ShuntingParser::parse_str("0 < x <= 1"),
+            Err("Can't chain non-associative operators '<' and '<='".to_string())
+        );
+        let rpn = ShuntingParser::parse_str("(a == b) == c").unwrap();
+        assert_eq!(rpn.to_string(), "(a == b) == c");
+
+        let grammar = Grammar {
+            chained_comparisons: true,
+            ..Grammar::default()
+        };
+        let rpn = ShuntingParser::parse_str_with("0 < x < 10", &grammar).unwrap();
+        assert_eq!(rpn.to_string(), "0 < x && x < 10");
+        let rpn = ShuntingParser::parse_str_with("a < f(b) + 1 <= c == -d", &grammar).unwrap();
+        assert_eq!(rpn.to_string(), "a < f(b) + 1 && f(b) + 1 <= c && c == -d");
+        let rpn = ShuntingParser::parse_str_with("x < (y < z) * 2", &grammar).unwrap();
+        assert_eq!(rpn.to_string(), "x < (y < z) * 2");
+    }
+
+    #[test]
+    fn spans() {
+        use crate::grammar::Grammar;
+        let grammar = Grammar {
+            chained_comparisons: true,
+            ..Grammar::default()
+        };
+        let src = "max(a, (b+1) *2) - |c| < if(d, 1, 2)! < 3";
+        let spanned = ShuntingParser::parse_str_spanned(src, &grammar).unwrap();
+        let texts: Vec<_> = (0..spanned.spans.len()).map(|i| spanned.text(i)).collect();
+        let expect = [
+            "a",
+            "b",
+            "1",
+            "(b+1)",
+            "2",
+            "(b+1) *2",
+            "max(a, (b+1) *2)",
+            "c",
+            "|c|",
+            "max(a, (b+1) *2) - |c|",
+            "d",
+            "d",
+            "1",
+            "1",
+            "2",
+            "2",
+            "if(d, 1, 2)",
+            "if(d, 1, 2)!",
+            "max(a, (b+1) *2) - |c| < if(d, 1, 2)!",
+            // the middle operand of the chain is repeated
+            "d",
+            "d",
+            "1",
+            "1",
+            "2",
+            "2",
+            "if(d, 1, 2)",
+            "if(d, 1, 2)!",
+            "3",
+            "if(d, 1, 2)! < 3",
+            src,
+        ];
+        assert_eq!(texts, expect);
+        let plain = ShuntingParser::parse_str_with(src, &grammar).unwrap();
+        assert_eq!(spanned.expr, plain);
+    }
+
+    #[test]
+    fn error_spans() {
+        use crate::grammar::Grammar;
+        let located =
+            |src| ShuntingParser::parse_str_located(src, &Grammar::default()).unwrap_err();
+        let error = |msg: &str, span| (msg.to_string(), span);
+        assert_eq!(located("1 + (2 * 3"), error("Missing Closing Paren", 4..5));
+        assert_eq!(located("1 + 2) * 3"), error("Missing Opening Paren", 5..6));
+        assert_eq!(
+            located("max(1, 2) x"),
+            error("Missing operator between operands", 10..11)
+        );
+        assert_eq!(
+            located("1 == 2 == 3"),
+            error("Can't chain non-associative operators '==' and '=='", 7..9)
+        );
+        assert_eq!(located("2 & 3"), error("Bad token: &", 2..3));
+    }
+
+    #[test]
+    fn dependencies() {
+        let expr = ShuntingParser::parse_str("max(x, y, f(x)) + sin(y) * max(1, 2, z)").unwrap();
+        let vars: Vec<_> = expr.variables().into_iter().collect();
+        assert_eq!(vars, ["x", "y", "z"]);
+        let funcs: Vec<_> = expr.functions().into_iter().collect();
+        let funcs: Vec<_> = funcs.iter().map(|(f, n)| (&f[..], *n)).collect();
+        assert_eq!(funcs, [("f", 1), ("max", 3), ("sin", 1)]);
+        let expr = ShuntingParser::parse_str("pi * 2").unwrap();
+        assert!(expr.functions().is_empty());
+        assert_eq!(expr.variables().len(), 1);
+
+        // indices are bound only in the summand
+        let expr = ShuntingParser::parse_str("sum(i * k, i, 1, n) + i").unwrap();
+        let vars: Vec<_> = expr.variables().into_iter().collect();
+        assert_eq!(vars, ["i", "k", "n"]);
+        let expr = ShuntingParser::parse_str("sum(sum(i * j, j, 1, i), i, 1, m)").unwrap();
+        let vars: Vec<_> = expr.variables().into_iter().collect();
+        assert_eq!(vars, ["m"]);
+    }
+
+    #[test]
+    fn expr_tree() {
+        let rpn = ShuntingParser::parse_str("if(x < 1, max(2, -x), 3!)").unwrap();
+        let tree = rpn.to_tree().unwrap();
+        assert_eq!(tree.token, MathToken::Function("if".to_string(), 3));
+        assert_eq!(
+            tree.args[2].args[0].token,
+            MathToken::Number(3.0, Radix::Dec)
+        );
+        assert_eq!(tree.to_rpn(), rpn);
+
+        // hand made expressions may not be trees
+        let x = || MathToken::Variable("x".to_string());
+        let plus = MathToken::BOp("+".to_string());
+        let rpn = RPNExpr(vec![x(), plus.clone()]);
+        assert_eq!(rpn.to_tree(), Err("Missing operands for +".to_string()));
+        assert_eq!(rpn.to_string(), "x +");
+        let rpn = RPNExpr(vec![x(), x(), x(), plus]);
+        assert_eq!(rpn.to_latex(), Err("Too many operands".to_string()));
+        assert_eq!(
+            rpn.to_rust_expr(&["x"]),
+            Err("Too many operands".to_string())
+        );
+        assert_eq!(RPNExpr(vec![]).to_string(), "");
+        let rpn = RPNExpr(vec![MathToken::Comma]);
+        assert_eq!(rpn.to_mathml(), Err("Bad token: Comma".to_string()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_formats() {
+        let rpn = ShuntingParser::parse_str("max(2, -x)").unwrap();
+        let flat = r#"[{"Number":[2.0,"Dec"]},{"Variable":"x"},{"UOp":["-","Prefix"]},{"Function":["max",2]}]"#;
+        assert_eq!(serde_json::to_string(&rpn).unwrap(), flat);
+        assert_eq!(serde_json::from_str::<RPNExpr>(flat).unwrap(), rpn);
+
+        let nested = concat!(
+            r#"{"token":{"Function":["max",2]},"args":["#,
+            r#"{"token":{"Number":[2.0,"Dec"]}},"#,
+            r#"{"token":{"UOp":["-","Prefix"]},"args":[{"token":{"Variable":"x"}}]}]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&rpn.to_tree().unwrap()).unwrap(),
+            nested
+        );
+        let tree: super::ExprTree = serde_json::from_str(nested).unwrap();
+        assert_eq!(tree.to_rpn(), rpn);
+
+        let rpn = ShuntingParser::parse_str("piecewise(x < 0, 0x1F, x)").unwrap();
+        let bytes = bincode::serialize(&rpn).unwrap();
+        assert_eq!(bincode::deserialize::<RPNExpr>(&bytes).unwrap(), rpn);
+
+        // quotes are left out and put back
+        let rpn = ShuntingParser::parse_str("if(x, 1, 2)").unwrap();
+        let json = serde_json::to_string(&rpn).unwrap();
+        assert!(!json.contains("Quote"));
+        assert_eq!(serde_json::from_str::<RPNExpr>(&json).unwrap(), rpn);
+        let error = |json| {
+            serde_json::from_str::<RPNExpr>(json)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"[{"Quote":1},{"Variable":"x"}]"#),
+            "Bad token: Quote(1)"
+        );
+        assert_eq!(
+            error(r#"[{"Variable":"x"},{"BOp":"+"}]"#),
+            "Missing operands for +"
+        );
+        assert_eq!(error(r#"[{"Unknown":"$"}]"#), "Bad token: Unknown(\"$\")");
+        assert_eq!(
+            serde_json::from_str::<RPNExpr>("[]").unwrap(),
+            RPNExpr(vec![])
+        );
+    }
 }
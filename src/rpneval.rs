@@ -1,6 +1,28 @@
-use crate::parser::RPNExpr;
+use crate::parser::{
+    binds_variable, bound_variables, is_lazy, FunctionDef, RPNExpr, ShuntingParser,
+};
 use crate::tokenizer::MathToken;
-use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// an error and the token it's about, when that's known
+type Located<'a> = (String, Option<&'a MathToken>);
+
+fn unlocated<'a>(error: String) -> Located<'a> {
+    (error, None)
+}
+
+// how deep user defined functions may call each other before we give up
+pub(crate) const MAX_CALL_DEPTH: usize = 256;
+
+// how many terms sum and prod may add up before we give up
+pub(crate) const MAX_TERMS: usize = 10_000_000;
+
+// integrals are refined until the error estimate is below this much of the
+// integral, and fail if that takes too many evaluations
+const INTEGRAL_TOLERANCE: f64 = 1e-10;
+const MAX_INTEGRAL_EVALUATIONS: usize = 1_000_000;
 
 // a shorthand for checking number of arguments before eval_fn
 macro_rules! nargs {
@@ -14,72 +36,395 @@ macro_rules! nargs {
 }
 
 #[derive(Debug, Clone)]
-pub struct MathContext(pub HashMap<String, f64>);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MathContext {
+    pub vars: HashMap<String, f64>,
+    pub funcs: HashMap<String, FunctionDef>,
+    // operators evaluated as calls to a user function or builtin, eg: a
+    // grammar's 'mod' mapped to 'fmod'
+    pub ops: HashMap<String, String>,
+}
 
 impl MathContext {
     pub fn new() -> MathContext {
         use std::f64::consts;
-        let mut cx = HashMap::new();
-        cx.insert("pi".to_string(), consts::PI);
-        cx.insert("e".to_string(), consts::E);
-        MathContext(cx)
+        let mut vars = HashMap::new();
+        vars.insert("pi".to_string(), consts::PI);
+        vars.insert("e".to_string(), consts::E);
+        MathContext {
+            vars,
+            funcs: HashMap::new(),
+            ops: HashMap::new(),
+        }
     }
 
     pub fn setvar(&mut self, var: &str, val: f64) {
-        self.0.insert(var.to_string(), val);
+        self.vars.insert(var.to_string(), val);
+    }
+
+    // define a function from a string like 'f(x, y) = x^2 + y^2'
+    pub fn define(&mut self, def: &str) -> Result<(), String> {
+        self.setfn(ShuntingParser::parse_definition(def)?)
+    }
+
+    pub fn setfn(&mut self, def: FunctionDef) -> Result<(), String> {
+        if is_lazy(&def.name) {
+            return Err(format!("Cannot redefine {}", def.name));
+        }
+        // check calls to known user functions (including itself) for arity
+        for token in def.body.0.iter() {
+            if let MathToken::Function(ref fname, arity) = *token {
+                let params = match self.funcs.get(fname) {
+                    _ if *fname == def.name => def.params.len(),
+                    Some(known) => known.params.len(),
+                    None => continue,
+                };
+                if arity != params {
+                    return Err(format!("Wrong number of arguments to {}", fname));
+                }
+            }
+        }
+        self.funcs.insert(def.name.clone(), def);
+        Ok(())
+    }
+
+    // evaluate the operator 'symbol' by calling 'fname' with its operands
+    pub fn setop(&mut self, symbol: &str, fname: &str) {
+        self.ops.insert(symbol.to_string(), fname.to_string());
+    }
+
+    // find every unbound variable and unknown function in 'rpn' before
+    // evaluating it, including those in the user functions it calls
+    pub fn check(&self, rpn: &RPNExpr) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        self.check_scoped(rpn, &[], &mut HashSet::new(), &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_scoped(
+        &self,
+        rpn: &RPNExpr,
+        params: &[String],
+        checked: &mut HashSet<String>,
+        errors: &mut Vec<String>,
+    ) {
+        let mut report = |error: String| {
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
+        };
+        let mut calls = Vec::new();
+        for (token, bound) in rpn.0.iter().zip(bound_variables(&rpn.0)) {
+            let (fname, arity) = match *token {
+                MathToken::Variable(ref var) => {
+                    if !bound && !params.contains(var) && !self.vars.contains_key(var) {
+                        report(format!("Unknown Variable: {}", var));
+                    }
+                    continue;
+                }
+                MathToken::Function(ref fname, arity) => (fname, arity),
+                MathToken::BOp(ref op) | MathToken::UOp(ref op, _) => match self.ops.get(op) {
+                    Some(fname) => (fname, token.arity()),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let known = match self.funcs.get(fname) {
+                Some(def) => def.params.len() == arity,
+                None if fname == "if" => arity == 3,
+                None if fname == "piecewise" => arity % 2 == 1,
+                None if binds_variable(fname) => arity == 4,
+                // builtins tell an unknown name from a bad argument count
+                None => match Self::eval_fn(fname, vec![0.0; arity]) {
+                    Err(ref error) if error.starts_with("Unknown") => {
+                        report(error.clone());
+                        continue;
+                    }
+                    result => result.is_ok(),
+                },
+            };
+            if !known {
+                report(format!("Wrong number of arguments to {}", fname));
+            } else if self.funcs.contains_key(fname) && checked.insert(fname.clone()) {
+                calls.push(&self.funcs[fname]);
+            }
+        }
+        for def in calls {
+            self.check_scoped(&def.body, &def.params, checked, errors);
+        }
     }
 
     pub fn eval(&self, rpn: &RPNExpr) -> Result<f64, String> {
+        self.eval_scoped(&rpn.0, &HashMap::new(), 0)
+            .map_err(|(error, _)| error)
+    }
+
+    // like eval but errors also give the index in 'rpn' of the token that
+    // failed, eg: the call that got the wrong number of arguments
+    pub fn eval_located(&self, rpn: &RPNExpr) -> Result<f64, (String, Option<usize>)> {
+        self.eval_scoped(&rpn.0, &HashMap::new(), 0)
+            .map_err(|(error, at)| {
+                let index =
+                    at.and_then(|at| rpn.0.iter().position(|token| std::ptr::eq(token, at)));
+                (error, index)
+            })
+    }
+
+    // evaluate with 'locals' shadowing context variables, 'depth' counts
+    // the user function calls we're nested in
+    fn eval_scoped<'a>(
+        &self,
+        rpn: &'a [MathToken],
+        locals: &HashMap<&str, f64>,
+        depth: usize,
+    ) -> Result<f64, Located<'a>> {
         let mut operands = Vec::new();
+        let mut quoted = Vec::new();
 
-        for token in rpn.0.iter() {
+        let mut tokens = rpn.iter();
+        while let Some(token) = tokens.next() {
+            let at = |error: String| (error, Some(token));
             match *token {
-                MathToken::Number(num) => operands.push(num),
-                MathToken::Variable(ref var) => match self.0.get(var) {
-                    Some(value) => operands.push(*value),
-                    None => return Err(format!("Unknown Variable: {}", var.to_string())),
-                },
-                MathToken::BOp(ref op) => {
-                    let r = operands
-                        .pop()
-                        .ok_or_else(|| "Wrong number of arguments".to_string())?;
-                    let l = operands
-                        .pop()
-                        .ok_or_else(|| "Wrong number of arguments".to_string())?;
-                    match &op[..] {
-                        "+" => operands.push(l + r),
-                        "-" => operands.push(l - r),
-                        "*" => operands.push(l * r),
-                        "/" => operands.push(l / r),
-                        "%" => operands.push(l % r),
-                        "^" => operands.push(l.powf(r)),
-                        _ => return Err(format!("Bad Token: {}", op.clone())),
+                MathToken::Quote(n) => {
+                    let rest = tokens.as_slice();
+                    if n > rest.len() {
+                        return Err(at("Wrong number of arguments".to_string()));
                     }
+                    quoted.push(&rest[..n]);
+                    tokens = rest[n..].iter();
                 }
-                MathToken::UOp(ref op) => {
-                    let o = operands
-                        .pop()
-                        .ok_or_else(|| "Wrong number of arguments".to_string())?;
-                    match &op[..] {
-                        "-" => operands.push(-o),
-                        "!" => operands.push(Self::eval_fn("tgamma", vec![o + 1.0])?),
-                        _ => return Err(format!("Bad Token: {}", op.clone())),
+                MathToken::Function(ref fname, arity) if is_lazy(fname) => {
+                    if arity > quoted.len() {
+                        return Err(at("Wrong number of arguments".to_string()));
                     }
+                    let cut = quoted.len() - arity;
+                    let args = quoted.split_off(cut);
+                    // errors inside the arguments keep the token they're about
+                    let value = self.eval_lazy(fname, &args, locals, depth);
+                    operands.push(value.map_err(|(error, inner)| (error, inner.or(Some(token))))?)
                 }
-                MathToken::Function(ref fname, arity) => {
-                    if arity > operands.len() {
-                        return Err("Wrong number of arguments".to_string());
-                    }
-                    let cut = operands.len() - arity;
-                    let args = operands.split_off(cut);
-                    operands.push(Self::eval_fn(fname, args)?)
-                }
-                _ => return Err(format!("Bad Token: {:?}", *token)),
+                _ => self
+                    .eval_token(token, &mut operands, locals, depth)
+                    .map_err(at)?,
             }
         }
         operands
             .pop()
-            .ok_or_else(|| "Wrong number of arguments".to_string())
+            .ok_or_else(|| ("Wrong number of arguments".to_string(), rpn.last()))
+    }
+
+    // evaluate a token that gets its arguments from 'operands'
+    fn eval_token(
+        &self,
+        token: &MathToken,
+        operands: &mut Vec<f64>,
+        locals: &HashMap<&str, f64>,
+        depth: usize,
+    ) -> Result<(), String> {
+        match *token {
+            MathToken::Number(num, _) => operands.push(num),
+            MathToken::Integer(num, _) => operands.push(num as f64),
+            // in SI units, eval_quantity checks the dimensions too
+            MathToken::Unit(ref unit) => operands.push(unit.scale),
+            MathToken::Variable(ref var) => {
+                match locals.get(&var[..]).or_else(|| self.vars.get(var)) {
+                    Some(value) => operands.push(*value),
+                    None => return Err(format!("Unknown Variable: {}", var)),
+                }
+            }
+            MathToken::BOp(ref op) => {
+                let r = operands
+                    .pop()
+                    .ok_or_else(|| "Wrong number of arguments".to_string())?;
+                let l = operands
+                    .pop()
+                    .ok_or_else(|| "Wrong number of arguments".to_string())?;
+                if let Some(fname) = self.ops.get(op) {
+                    operands.push(self.call(fname, vec![l, r], depth)?);
+                    return Ok(());
+                }
+                match &op[..] {
+                    "+" => operands.push(l + r),
+                    "-" => operands.push(l - r),
+                    "*" => operands.push(l * r),
+                    "/" => operands.push(l / r),
+                    "%" => operands.push(l % r),
+                    "^" => operands.push(l.powf(r)),
+                    "<" => operands.push(truth(l < r)),
+                    "<=" => operands.push(truth(l <= r)),
+                    ">" => operands.push(truth(l > r)),
+                    ">=" => operands.push(truth(l >= r)),
+                    "==" => operands.push(truth(l == r)),
+                    "!=" => operands.push(truth(l != r)),
+                    "&&" => operands.push(truth(l != 0.0 && r != 0.0)),
+                    "in" => operands.push(l / r),
+                    _ => return Err(format!("Bad Token: {}", op.clone())),
+                }
+            }
+            MathToken::UOp(ref op, _) => {
+                let o = operands
+                    .pop()
+                    .ok_or_else(|| "Wrong number of arguments".to_string())?;
+                if let Some(fname) = self.ops.get(op) {
+                    operands.push(self.call(fname, vec![o], depth)?);
+                    return Ok(());
+                }
+                match &op[..] {
+                    "-" => operands.push(-o),
+                    "!" => operands.push(Self::eval_fn("tgamma", vec![o + 1.0])?),
+                    _ => return Err(format!("Bad Token: {}", op.clone())),
+                }
+            }
+            MathToken::Function(ref fname, arity) => {
+                if arity > operands.len() {
+                    return Err("Wrong number of arguments".to_string());
+                }
+                let cut = operands.len() - arity;
+                let args = operands.split_off(cut);
+                operands.push(self.call(fname, args, depth)?);
+            }
+            _ => return Err(format!("Bad Token: {:?}", *token)),
+        }
+        Ok(())
+    }
+
+    // user functions shadow builtins
+    fn call(&self, fname: &str, args: Vec<f64>, depth: usize) -> Result<f64, String> {
+        match self.funcs.get(fname) {
+            Some(def) => self.eval_user_fn(def, args, depth),
+            None => Self::eval_fn(fname, args),
+        }
+    }
+
+    fn eval_user_fn(&self, def: &FunctionDef, args: Vec<f64>, depth: usize) -> Result<f64, String> {
+        if args.len() != def.params.len() {
+            return Err(format!("Wrong number of arguments to {}", def.name));
+        }
+        if depth >= MAX_CALL_DEPTH {
+            return Err(format!("Recursion too deep in {}", def.name));
+        }
+        let locals = def.params.iter().map(|p| &p[..]).zip(args).collect();
+        // errors in the body are about the call
+        self.eval_scoped(&def.body.0, &locals, depth + 1)
+            .map_err(|(error, _)| error)
+    }
+
+    // lazy functions get their arguments unevaluated
+    fn eval_lazy<'a>(
+        &self,
+        fname: &str,
+        args: &[&'a [MathToken]],
+        locals: &HashMap<&str, f64>,
+        depth: usize,
+    ) -> Result<f64, Located<'a>> {
+        let known = match fname {
+            "if" => args.len() == 3,
+            "piecewise" => args.len() % 2 == 1,
+            "sum" | "prod" | "integrate" => args.len() == 4,
+            _ => return Err((format!("Unknown function: {}", fname), None)),
+        };
+        if !known {
+            return Err(("Wrong number of arguments".to_string(), None));
+        }
+        match fname {
+            "if" | "piecewise" => self.eval_piecewise(args, locals, depth),
+            "sum" | "prod" => self.eval_series(fname, args, locals, depth),
+            _ => self.eval_integral(args, locals, depth),
+        }
+    }
+
+    // sum(expr, i, a, b) and prod(expr, i, a, b) evaluate 'expr' with 'i'
+    // set to a, a + 1, ... up to b
+    fn eval_series<'a>(
+        &self,
+        fname: &str,
+        args: &[&'a [MathToken]],
+        locals: &HashMap<&str, f64>,
+        depth: usize,
+    ) -> Result<f64, Located<'a>> {
+        let index = index_variable(fname, args[1]).map_err(unlocated)?;
+        let from = self.eval_scoped(args[2], locals, depth)?;
+        let to = self.eval_scoped(args[3], locals, depth)?;
+        let mut scope: HashMap<&str, f64> = locals.iter().map(|(&k, &v)| (k, v)).collect();
+        let mut total = if fname == "sum" { 0.0 } else { 1.0 };
+        for i in series_indices(from, to).map_err(unlocated)? {
+            scope.insert(index, i);
+            let term = self.eval_scoped(args[0], &scope, depth)?;
+            if fname == "sum" {
+                total += term;
+            } else {
+                total *= term;
+            }
+        }
+        Ok(total)
+    }
+
+    fn eval_integral<'a>(
+        &self,
+        args: &[&'a [MathToken]],
+        locals: &HashMap<&str, f64>,
+        depth: usize,
+    ) -> Result<f64, Located<'a>> {
+        let var = index_variable("integrate", args[1]).map_err(unlocated)?;
+        let from = self.eval_scoped(args[2], locals, depth)?;
+        let to = self.eval_scoped(args[3], locals, depth)?;
+        let mut scope: HashMap<&str, f64> = locals.iter().map(|(&k, &v)| (k, v)).collect();
+        // integral() only passes the message along, keep the token aside
+        let mut failed = None;
+        let mut integrand = |x| {
+            scope.insert(var, x);
+            self.eval_scoped(args[0], &scope, depth)
+                .map_err(|(error, at)| {
+                    failed = at;
+                    error
+                })
+        };
+        let result = integral(&mut integrand, from, to);
+        result
+            .map(|(value, _)| value)
+            .map_err(|error| (error, failed))
+    }
+
+    // integrate 'rpn' over 'var' from 'a' to 'b', returns the integral
+    // along with an estimate of its error
+    pub fn integrate(
+        &self,
+        rpn: &RPNExpr,
+        var: &str,
+        a: f64,
+        b: f64,
+    ) -> Result<(f64, f64), String> {
+        let mut locals = HashMap::new();
+        let mut integrand = |x| {
+            locals.insert(var, x);
+            self.eval_scoped(&rpn.0, &locals, 0)
+                .map_err(|(error, _)| error)
+        };
+        integral(&mut integrand, a, b)
+    }
+
+    // args are (condition, value) pairs followed by a default value,
+    // only the value of the first true (non-zero) condition is evaluated
+    fn eval_piecewise<'a>(
+        &self,
+        args: &[&'a [MathToken]],
+        locals: &HashMap<&str, f64>,
+        depth: usize,
+    ) -> Result<f64, Located<'a>> {
+        for case in args.chunks(2) {
+            match *case {
+                [cond, value] if self.eval_scoped(cond, locals, depth)? != 0.0 => {
+                    return self.eval_scoped(value, locals, depth)
+                }
+                [default] => return self.eval_scoped(default, locals, depth),
+                _ => (),
+            }
+        }
+        unreachable!()
     }
 
     fn eval_fn(fname: &str, args: Vec<f64>) -> Result<f64, String> {
@@ -105,6 +450,103 @@ impl MathContext {
     }
 }
 
+// the index argument of sum, prod and integrate, which must be a variable
+pub(crate) fn index_variable<'a>(fname: &str, arg: &'a [MathToken]) -> Result<&'a str, String> {
+    match *arg {
+        [MathToken::Variable(ref name)] => Ok(name),
+        _ => Err(format!("Expected a variable to {} over", fname)),
+    }
+}
+
+// the values the index of sum and prod takes, 'from' up to 'to' in steps
+// of one, none if 'to' is smaller
+pub(crate) fn series_indices(from: f64, to: f64) -> Result<impl Iterator<Item = f64>, String> {
+    if !from.is_finite() || !to.is_finite() {
+        return Err("Bounds must be finite".to_string());
+    }
+    let count = ((to - from).floor() + 1.0).max(0.0);
+    if count > MAX_TERMS as f64 {
+        return Err(format!("Too many terms: {}", count));
+    }
+    Ok((0..count as usize).map(move |k| from + k as f64))
+}
+
+// one step of Simpson's rule over [a, b] with the midpoint 'm'
+struct Simpson {
+    a: (f64, f64),
+    m: (f64, f64),
+    b: (f64, f64),
+    area: f64,
+}
+
+impl Simpson {
+    fn new(
+        f: &mut impl FnMut(f64) -> Result<f64, String>,
+        a: (f64, f64),
+        b: (f64, f64),
+    ) -> Result<Simpson, String> {
+        let m = (a.0 + b.0) / 2.0;
+        let m = (m, f(m)?);
+        let area = (b.0 - a.0) / 6.0 * (a.1 + 4.0 * m.1 + b.1);
+        Ok(Simpson { a, m, b, area })
+    }
+}
+
+// adaptive Simpson's rule, returns the integral of 'f' over [a, b] and an
+// estimate of its error
+pub(crate) fn integral(
+    f: &mut impl FnMut(f64) -> Result<f64, String>,
+    a: f64,
+    b: f64,
+) -> Result<(f64, f64), String> {
+    if !a.is_finite() || !b.is_finite() {
+        return Err("Bounds must be finite".to_string());
+    }
+    let a = (a, f(a)?);
+    let b = (b, f(b)?);
+    let whole = Simpson::new(f, a, b)?;
+    // relative to the first estimate, but no tighter than absolute for
+    // integrals near 0
+    let tolerance = INTEGRAL_TOLERANCE * whole.area.abs().max(1.0);
+    let mut budget = MAX_INTEGRAL_EVALUATIONS;
+    let (value, error) = refine(f, whole, tolerance, &mut budget)?;
+    if error > tolerance {
+        return Err("Integral did not converge".to_string());
+    }
+    Ok((value, error))
+}
+
+// split in halves until their sum agrees with the whole, Richardson
+// extrapolation then takes out most of the remaining error
+fn refine(
+    f: &mut impl FnMut(f64) -> Result<f64, String>,
+    whole: Simpson,
+    tolerance: f64,
+    budget: &mut usize,
+) -> Result<(f64, f64), String> {
+    let left = Simpson::new(f, whole.a, whole.m)?;
+    let right = Simpson::new(f, whole.m, whole.b)?;
+    *budget = match budget.checked_sub(2) {
+        Some(budget) => budget,
+        None => return Err("Integral did not converge".to_string()),
+    };
+    let delta = left.area + right.area - whole.area;
+    if !delta.is_finite() || delta.abs() <= 15.0 * tolerance {
+        return Ok((left.area + right.area + delta / 15.0, delta.abs() / 15.0));
+    }
+    let (left, left_error) = refine(f, left, tolerance / 2.0, budget)?;
+    let (right, right_error) = refine(f, right, tolerance / 2.0, budget)?;
+    Ok((left + right, left_error + right_error))
+}
+
+fn truth(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
 impl Default for MathContext {
     fn default() -> Self {
         Self::new()
@@ -115,6 +557,7 @@ impl Default for MathContext {
 mod tests {
     use super::MathContext;
     use crate::parser::ShuntingParser;
+    use crate::tokenizer::MathToken;
 
     macro_rules! fuzzy_eq {
         ($lhs:expr, $rhs:expr) => {
@@ -135,6 +578,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn test_eval3() {
         let expr = ShuntingParser::parse_str("(-(1-9^2) / (1 + 6^2))^0.5").unwrap();
         fuzzy_eq!(
@@ -178,4 +622,285 @@ mod tests {
         let expr = ShuntingParser::parse_str("-2^-3").unwrap();
         fuzzy_eq!(MathContext::new().eval(&expr).unwrap(), -0.125);
     }
+
+    #[test]
+    fn test_eval9() {
+        let expr = ShuntingParser::parse_str("||2 - 5| - 7| * |-2|").unwrap();
+        fuzzy_eq!(MathContext::new().eval(&expr).unwrap(), 8.0);
+    }
+
+    #[test]
+    fn test_eval10() {
+        let expr = ShuntingParser::parse_str("0xFF - 0b1010 * 0o10 + 0x1.8p1").unwrap();
+        fuzzy_eq!(MathContext::new().eval(&expr).unwrap(), 178.0);
+    }
+
+    #[test]
+    fn user_functions() {
+        let mut cx = MathContext::new();
+        cx.define("f(x, y) = x^2 + y^2").unwrap();
+        cx.define("g(x) = f(x, 2 * x) / e").unwrap();
+        cx.define("tau() = 2 * pi").unwrap();
+        cx.setvar("x", 100.0);
+
+        let expr = ShuntingParser::parse_str("f(3, 4)").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 25.0);
+        let expr = ShuntingParser::parse_str("g(1) * e + x").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 105.0);
+        let expr = ShuntingParser::parse_str("tau()").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 2.0 * std::f64::consts::PI);
+
+        // parameters are local to the function body
+        let expr = ShuntingParser::parse_str("y").unwrap();
+        assert_eq!(cx.eval(&expr), Err("Unknown Variable: y".to_string()));
+
+        // user functions shadow built-ins
+        cx.define("sin(x) = x").unwrap();
+        let expr = ShuntingParser::parse_str("sin(3)").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn user_function_errors() {
+        let mut cx = MathContext::new();
+        cx.define("f(x, y) = x * y").unwrap();
+        assert_eq!(
+            cx.define("g(x) = f(x)"),
+            Err("Wrong number of arguments to f".to_string())
+        );
+        assert_eq!(
+            cx.define("h(x) = h(x, x)"),
+            Err("Wrong number of arguments to h".to_string())
+        );
+
+        let expr = ShuntingParser::parse_str("f(1, 2, 3)").unwrap();
+        assert_eq!(
+            cx.eval(&expr),
+            Err("Wrong number of arguments to f".to_string())
+        );
+
+        cx.define("loop(x) = loop(x + 1)").unwrap();
+        let expr = ShuntingParser::parse_str("loop(0)").unwrap();
+        assert_eq!(
+            cx.eval(&expr),
+            Err("Recursion too deep in loop".to_string())
+        );
+
+        // errors point at the token that failed
+        let expr = ShuntingParser::parse_str("f(1, 2) + f(1, 2, 3)").unwrap();
+        assert_eq!(
+            cx.eval_located(&expr),
+            Err(("Wrong number of arguments to f".to_string(), Some(6)))
+        );
+        let expr = ShuntingParser::parse_str("if(1, z, 0)").unwrap();
+        let z = MathToken::Variable("z".to_string());
+        let at = expr.0.iter().position(|token| *token == z);
+        assert_eq!(
+            cx.eval_located(&expr),
+            Err(("Unknown Variable: z".to_string(), at))
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        let mut cx = MathContext::new();
+        cx.define("fact(n) = if(n <= 1, 1, n * fact(n - 1))")
+            .unwrap();
+        cx.define("sign(x) = piecewise(x < 0, -1, x == 0, 0, 1)")
+            .unwrap();
+
+        let expr = ShuntingParser::parse_str("fact(5) + fact(0)").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 121.0);
+        let expr = ShuntingParser::parse_str("sign(-3) + 2 * sign(0) + 4 * sign(7)").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 3.0);
+        let expr = ShuntingParser::parse_str("if(1 != 2, 3 >= 3, 2 > 3) + piecewise(5)").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 6.0);
+
+        let grammar = crate::grammar::Grammar {
+            chained_comparisons: true,
+            ..Default::default()
+        };
+        cx.define("inside(x) = 0 < x && x < 10").unwrap();
+        let expr = ShuntingParser::parse_str_with("inside(3) + (0 < 12 < 10)", &grammar).unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn conditionals_are_lazy() {
+        let cx = MathContext::new();
+        // the branch not taken is never evaluated
+        let expr = ShuntingParser::parse_str("if(pi > 3, 1, unknown)").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 1.0);
+        let expr = ShuntingParser::parse_str("piecewise(0, nope(1), 1, 2, nope(3))").unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 2.0);
+        let expr = ShuntingParser::parse_str("if(pi < 3, 1, unknown)").unwrap();
+        assert_eq!(cx.eval(&expr), Err("Unknown Variable: unknown".to_string()));
+
+        let expr = ShuntingParser::parse_str("if(1, 2)").unwrap();
+        assert_eq!(cx.eval(&expr), Err("Wrong number of arguments".to_string()));
+        let expr = ShuntingParser::parse_str("piecewise(1, 2)").unwrap();
+        assert_eq!(cx.eval(&expr), Err("Wrong number of arguments".to_string()));
+        assert_eq!(
+            MathContext::new().define("if(a, b, c) = a"),
+            Err("Cannot redefine if".to_string())
+        );
+    }
+
+    #[test]
+    fn check() {
+        let mut cx = MathContext::new();
+        cx.setvar("x", 1.0);
+        cx.define("f(a) = a * scale + g(a)").unwrap();
+        cx.define("h(a) = f(a) + h(a - 1)").unwrap();
+        let expr = ShuntingParser::parse_str("f(x) + y * sin(x, y) + max() + y + nope(2)").unwrap();
+        let errors = [
+            "Unknown Variable: y",
+            "Wrong number of arguments to sin",
+            "Wrong number of arguments to max",
+            "Unknown function: nope",
+            "Unknown Variable: scale",
+            "Unknown function: g",
+        ];
+        assert_eq!(
+            cx.check(&expr),
+            Err(errors.iter().map(|e| e.to_string()).collect())
+        );
+
+        let expr = ShuntingParser::parse_str("if(x, pi, piecewise(x, 1)) + h(x, 2)").unwrap();
+        let errors = [
+            "Wrong number of arguments to piecewise",
+            "Wrong number of arguments to h",
+        ];
+        assert_eq!(
+            cx.check(&expr),
+            Err(errors.iter().map(|e| e.to_string()).collect())
+        );
+
+        cx.setvar("scale", 2.0);
+        cx.define("g(a) = -a").unwrap();
+        let expr = ShuntingParser::parse_str("h(x) + atan2(x, 1) + rand(x)").unwrap();
+        assert_eq!(cx.check(&expr), Ok(()));
+    }
+
+    #[test]
+    fn custom_operators() {
+        use crate::grammar::{Assoc, Grammar, Operator};
+        let mut grammar = Grammar::default();
+        grammar
+            .operators
+            .add(Operator::infix("mod", 30, Assoc::Left));
+        grammar.operators.add(Operator::infix("⊕", 25, Assoc::Left));
+        grammar.operators.add(Operator::postfix("°", 60));
+        let mut cx = MathContext::new();
+        cx.define("avg(a, b) = (a + b) / 2").unwrap();
+        cx.define("rad(x) = x * pi / 180").unwrap();
+        cx.setop("⊕", "avg");
+        cx.setop("mod", "max");
+        cx.setop("°", "rad");
+
+        let expr = ShuntingParser::parse_str_with("1 ⊕ 2 mod 3 * 2", &grammar).unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 3.5);
+        let expr = ShuntingParser::parse_str_with("sin(90°) * 8 + 10 % 3", &grammar).unwrap();
+        fuzzy_eq!(cx.eval(&expr).unwrap(), 9.0);
+
+        let expr = ShuntingParser::parse_str_with("1 ⊕ 2 + 3°", &grammar).unwrap();
+        assert_eq!(cx.check(&expr), Ok(()));
+        cx.setop("°", "deg");
+        assert_eq!(
+            cx.check(&expr),
+            Err(vec!["Unknown function: deg".to_string()])
+        );
+
+        cx.ops.clear();
+        let expr = ShuntingParser::parse_str_with("1 ⊕ 2", &grammar).unwrap();
+        assert_eq!(cx.eval(&expr), Err("Bad Token: ⊕".to_string()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_context() {
+        let mut cx = MathContext::new();
+        cx.define("f(x) = x^2 + k").unwrap();
+        cx.setvar("k", 1.5);
+        cx.setop("⊕", "f");
+        let json = serde_json::to_string(&cx).unwrap();
+        let restored: MathContext = serde_json::from_str(&json).unwrap();
+        let bytes = bincode::serialize(&cx).unwrap();
+        let decoded: MathContext = bincode::deserialize(&bytes).unwrap();
+        let expr = ShuntingParser::parse_str("f(2) + pi").unwrap();
+        for cx in [restored, decoded].iter() {
+            fuzzy_eq!(cx.eval(&expr).unwrap(), 5.5 + std::f64::consts::PI);
+            assert_eq!(cx.ops.get("⊕"), Some(&"f".to_string()));
+        }
+    }
+
+    #[test]
+    fn series() {
+        let mut cx = MathContext::new();
+        let eval =
+            |cx: &MathContext, expr: &str| cx.eval(&ShuntingParser::parse_str(expr).unwrap());
+        assert_eq!(eval(&cx, "sum(i^2, i, 1, 10)"), Ok(385.0));
+        assert_eq!(eval(&cx, "prod(k, k, 1, 5)"), Ok(120.0));
+        assert_eq!(eval(&cx, "sum(sum(i * j, j, 1, i), i, 1, 3)"), Ok(25.0));
+        assert_eq!(eval(&cx, "sum(i, i, 1, 0) + prod(i, i, 1, 0)"), Ok(1.0));
+
+        // the index shadows a variable of the same name, which the bounds see
+        cx.setvar("i", 3.0);
+        assert_eq!(eval(&cx, "sum(i, i, 1, i) + i"), Ok(9.0));
+        assert_eq!(cx.vars["i"], 3.0);
+        cx.define("tri(n) = sum(k, k, 1, n)").unwrap();
+        assert_eq!(eval(&cx, "tri(4) + sum(tri(k), k, 1, 3)"), Ok(20.0));
+
+        assert_eq!(
+            eval(&cx, "sum(1, 2, 0, 3)"),
+            Err("Expected a variable to sum over".to_string())
+        );
+        assert_eq!(
+            eval(&cx, "sum(k, k, 0)"),
+            Err("Wrong number of arguments".to_string())
+        );
+        assert_eq!(
+            eval(&cx, "sum(k, k, 0, 1e12)"),
+            Err("Too many terms: 1000000000001".to_string())
+        );
+        assert_eq!(
+            cx.check(&ShuntingParser::parse_str("sum(i * n, i, 1, m) + prod(i, i, 1)").unwrap()),
+            Err(vec![
+                "Unknown Variable: n".to_string(),
+                "Unknown Variable: m".to_string(),
+                "Wrong number of arguments to prod".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn integrals() {
+        let mut cx = MathContext::new();
+        let eval =
+            |cx: &MathContext, expr: &str| cx.eval(&ShuntingParser::parse_str(expr).unwrap());
+        assert_eq!(eval(&cx, "integrate(x^2, x, 0, 3)"), Ok(9.0));
+        fuzzy_eq!(eval(&cx, "integrate(sin(x), x, 0, pi)").unwrap(), 2.0);
+        fuzzy_eq!(eval(&cx, "integrate(abs(x), x, -1, 2)").unwrap(), 2.5);
+        fuzzy_eq!(
+            eval(&cx, "integrate(integrate(x * y, y, 0, x), x, 0, 2)").unwrap(),
+            2.0
+        );
+        cx.define("f(a) = integrate(t^a, t, 0, 1)").unwrap();
+        fuzzy_eq!(eval(&cx, "f(3)").unwrap(), 0.25);
+        assert_eq!(
+            eval(&cx, "integrate(x, x, 0, 1 / 0)"),
+            Err("Bounds must be finite".to_string())
+        );
+        let oscillating = eval(&cx, "integrate(1e6 * sin(x)^2, x, 0, 1000)").unwrap();
+        fuzzy_eq!(oscillating / (1e6 * (500.0 - (2000.0f64).sin() / 4.0)), 1.0);
+        assert_eq!(
+            eval(&cx, "integrate(sin(1 / x), x, 1e-9, 1)"),
+            Err("Integral did not converge".to_string())
+        );
+
+        let expr = ShuntingParser::parse_str("x^0.5").unwrap();
+        let (value, error) = cx.integrate(&expr, "x", 0.0, 1.0).unwrap();
+        assert!((value - 2.0 / 3.0).abs() < 1e-8);
+        assert!(error < 1e-8);
+    }
 }
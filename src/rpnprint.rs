@@ -1,98 +1,516 @@
-use crate::parser::{precedence, Assoc, RPNExpr};
-use crate::tokenizer::MathToken;
+use crate::grammar::{Assoc, Fixity, Operator, OperatorTable};
+use crate::parser::{RPNExpr, SpannedExpr};
+use crate::tokenizer::{MathToken, Radix};
 use std::fmt;
 
 #[derive(Debug, Clone)]
-enum AST<'a> {
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum AST<'a> {
     Leaf(&'a MathToken),
     Node(&'a MathToken, Vec<AST<'a>>),
 }
 
 impl RPNExpr {
-    fn build_ast(&self) -> AST {
+    // the tree of an expression, which fails for one assembled by hand
+    // that isn't a single well formed tree
+    pub(crate) fn build_ast(&self) -> Result<AST<'_>, String> {
         let mut ops = Vec::new();
         for token in self.0.iter() {
-            match *token {
-                MathToken::Number(_) | MathToken::Variable(_) => ops.push(AST::Leaf(token)),
-                MathToken::Function(_, arity) => {
-                    let n = ops.len() - arity;
-                    let operands = ops.split_off(n);
-                    ops.push(AST::Node(token, operands));
+            let name = match *token {
+                MathToken::Number(..)
+                | MathToken::Integer(..)
+                | MathToken::Variable(_)
+                | MathToken::Unit(_) => {
+                    ops.push(AST::Leaf(token));
+                    continue;
                 }
-                MathToken::BOp(_) => {
-                    let n = ops.len() - 2;
-                    let operands = ops.split_off(n);
-                    ops.push(AST::Node(token, operands));
-                }
-                MathToken::UOp(_) => {
-                    let n = ops.len() - 1;
-                    let operands = ops.split_off(n);
-                    ops.push(AST::Node(token, operands));
-                }
-                _ => unreachable!(),
+                MathToken::Function(ref name, _) => name,
+                MathToken::BOp(ref op) | MathToken::UOp(ref op, _) => op,
+                MathToken::Quote(_) => continue, // lazy args are regular subtrees
+                _ => return Err(format!("Bad token: {:?}", token)),
+            };
+            if token.arity() > ops.len() {
+                return Err(format!("Missing operands for {}", name));
             }
+            let operands = ops.split_off(ops.len() - token.arity());
+            ops.push(AST::Node(token, operands));
+        }
+        match ops.len() {
+            0 => Err("Empty expression".to_string()),
+            1 => Ok(ops.pop().unwrap()),
+            _ => Err("Too many operands".to_string()),
+        }
+    }
+}
+
+// Where to put parens when printing
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Parens {
+    // where precedence and associativity need them, '2+(3+4)' keeps its
+    // parens as they change the order of evaluation
+    #[default]
+    Precedence,
+    // drop the parens that algebraic associativity makes redundant, so
+    // '2+(3+4)' prints as '2 + 3 + 4'
+    Minimal,
+    // reproduce the source, grouping and whitespace included, only a
+    // SpannedExpr knows it, other expressions print as with Precedence
+    Faithful,
+}
+
+// Knobs to tweak how expressions are printed, the defaults are used by Display
+#[derive(Debug, Clone)]
+pub struct PrintOptions {
+    // print abs(x) as |x|
+    pub abs_bars: bool,
+    pub parens: Parens,
+    // the table the expression was parsed with, to know where parens go
+    pub operators: OperatorTable,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        // a superset of the default operators, so integer mode and units
+        // print too
+        let mut operators = OperatorTable::integer();
+        operators.add(Operator::infix("in", 1, Assoc::Left));
+        PrintOptions {
+            abs_bars: false,
+            parens: Parens::default(),
+            operators,
+        }
+    }
+}
+
+// A printed operand and whether precedence asks for parens around it,
+// notations that group by other means like \frac{}{} can ignore them
+pub(crate) struct Operand<'a> {
+    pub text: String,
+    pub parens: bool,
+    pub ast: &'a AST<'a>,
+}
+
+// How a notation writes each piece of an expression, walking the tree
+// and deciding where parens go is shared by all of them
+pub(crate) trait Notation {
+    fn leaf(&self, token: &MathToken) -> String;
+    fn group(&self, text: &str) -> String;
+    fn unary(&self, op: &str, fixity: Fixity, operand: Operand) -> String;
+    fn binary(&self, op: &str, lhs: Operand, rhs: Operand) -> String;
+    fn function(&self, name: &str, args: Vec<String>) -> String;
+
+    // a number with a unit like '3 m', it prints as one operand
+    fn quantity(&self, number: String, unit: String) -> String {
+        format!("{} {}", number, unit)
+    }
+
+    fn operand(&self, operand: &Operand) -> String {
+        match operand.parens {
+            true => self.group(&operand.text),
+            false => operand.text.clone(),
+        }
+    }
+}
+
+pub(crate) fn walk<'a, N: Notation>(
+    notation: &N,
+    root: &'a AST<'a>,
+    opts: &PrintOptions,
+) -> (String, (usize, Assoc)) {
+    let (prec, assoc) = match root {
+        // a negative number reads back as a negation, '(-1.5)^2' not '-1.5^2'
+        AST::Leaf(MathToken::Number(x, _)) if x.is_sign_negative() => {
+            let minus = MathToken::UOp("-".to_string(), Fixity::Prefix);
+            opts.operators.precedence(&minus)
+        }
+        AST::Leaf(token) | AST::Node(token, _) => opts.operators.precedence(token),
+    };
+    let text = match root {
+        AST::Leaf(token) => notation.leaf(token),
+        AST::Node(_, args) if is_quantity(root) => {
+            let (number, unit) = (token(&args[0]), token(&args[1]));
+            let text = notation.quantity(notation.leaf(number), notation.leaf(unit));
+            return (text, (usize::MAX, Assoc::None));
+        }
+        AST::Node(MathToken::UOp(ref op, fixity), args) => {
+            let (text, (sub_prec, _)) = walk(notation, &args[0], opts);
+            let parens = prec > sub_prec;
+            let ast = &args[0];
+            notation.unary(op, *fixity, Operand { text, parens, ast })
+        }
+        AST::Node(MathToken::BOp(ref op), args) => {
+            let (lhs, (lhs_prec, _)) = walk(notation, &args[0], opts);
+            let (rhs, (rhs_prec, _)) = walk(notation, &args[1], opts);
+            let regroups = opts.parens == Parens::Minimal && associates(op, &args[1]);
+            let lhs = Operand {
+                text: lhs,
+                // '(3 m)^2' keeps its parens or the unit would take the power
+                parens: prec > lhs_prec
+                    || (prec == lhs_prec && assoc != Assoc::Left)
+                    || (op == "^" && is_quantity(&args[0])),
+                ast: &args[0],
+            };
+            let rhs = Operand {
+                text: rhs,
+                parens: prec > rhs_prec || (prec == rhs_prec && assoc != Assoc::Right && !regroups),
+                ast: &args[1],
+            };
+            notation.binary(op, lhs, rhs)
+        }
+        AST::Node(MathToken::Function(ref func, _), args) => {
+            let args = args.iter().map(|arg| walk(notation, arg, opts).0);
+            notation.function(func, args.collect())
+        }
+        AST::Node(..) => unreachable!(),
+    };
+    (text, (prec, assoc))
+}
+
+// products of a number and a unit, as the tokenizer reads '3 m'
+pub(crate) fn is_quantity(ast: &AST) -> bool {
+    match ast {
+        AST::Node(MathToken::BOp(ref op), args) if op == "*" => matches!(
+            (&args[0], &args[1]),
+            (
+                AST::Leaf(MathToken::Number(..) | MathToken::Integer(..)),
+                AST::Leaf(MathToken::Unit(_))
+            )
+        ),
+        _ => false,
+    }
+}
+
+fn token<'a>(ast: &AST<'a>) -> &'a MathToken {
+    match *ast {
+        AST::Leaf(token) | AST::Node(token, _) => token,
+    }
+}
+
+// products like '2x' or '3 sin(x)' that typeset fine without a dot
+pub(crate) fn implicit_product(lhs: &Operand, rhs: &Operand) -> bool {
+    let base = match rhs.ast {
+        AST::Node(MathToken::BOp(ref op), args) if op == "^" => &args[0],
+        ast => ast,
+    };
+    let lhs_number = matches!(lhs.ast, AST::Leaf(MathToken::Number(..)));
+    let rhs_named = matches!(
+        base,
+        AST::Leaf(MathToken::Variable(_)) | AST::Node(MathToken::Function(..), _)
+    );
+    lhs_number && rhs_named && !lhs.parens && !rhs.parens
+}
+
+// The plain text notation expressions are parsed from
+struct Plain<'a>(&'a PrintOptions);
+
+impl<'a> Notation for Plain<'a> {
+    fn leaf(&self, token: &MathToken) -> String {
+        match *token {
+            MathToken::Number(x, radix) => print_number(x, radix),
+            MathToken::Integer(n, radix) => print_integer(n, radix),
+            MathToken::Variable(ref x) => x.to_string(),
+            MathToken::Unit(ref unit) => unit.name.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn group(&self, text: &str) -> String {
+        format!("({})", text)
+    }
+
+    fn unary(&self, op: &str, fixity: Fixity, operand: Operand) -> String {
+        match fixity {
+            Fixity::Postfix => format!("{}{}", self.operand(&operand), op),
+            _ => format!("{}{}", op, self.operand(&operand)),
+        }
+    }
+
+    fn binary(&self, op: &str, lhs: Operand, rhs: Operand) -> String {
+        format!("{} {} {}", self.operand(&lhs), op, self.operand(&rhs))
+    }
+
+    fn function(&self, name: &str, args: Vec<String>) -> String {
+        match &args[..] {
+            [arg] if name == "abs" && self.0.abs_bars => format!("|{}|", arg),
+            _ => format!("{}({})", name, args.join(", ")),
+        }
+    }
+}
+
+// can 'a op (b rhs c)' be regrouped as '(a op b) rhs c'
+fn associates(op: &str, rhs: &AST) -> bool {
+    match rhs {
+        AST::Node(MathToken::BOp(ref rhs), _) => matches!(
+            (op, &rhs[..]),
+            ("+", "+" | "-")
+                | ("*", "*" | "/")
+                | ("&&", "&&")
+                | ("&", "&")
+                | ("|", "|")
+                | ("xor", "xor")
+        ),
+        _ => false,
+    }
+}
+
+pub(crate) fn print_integer(n: u64, radix: Radix) -> String {
+    match radix {
+        Radix::Bin => format!("0b{:b}", n),
+        Radix::Oct => format!("0o{:o}", n),
+        Radix::Dec => n.to_string(),
+        Radix::Hex => format!("0x{:X}", n),
+    }
+}
+
+// print numbers in the radix they were written in, falling back to decimal
+pub(crate) fn print_number(x: f64, radix: Radix) -> String {
+    let sign = if x.is_sign_negative() { "-" } else { "" };
+    let integral = x.fract() == 0.0 && x.abs() < 2f64.powi(64);
+    match radix {
+        Radix::Bin | Radix::Oct | Radix::Hex if integral => {
+            format!("{}{}", sign, print_integer(x.abs() as u64, radix))
+        }
+        Radix::Hex if x.is_finite() => {
+            // hex float, 0x1.<52 bits of mantissa>p<exponent>
+            let bits = x.abs().to_bits();
+            let mantissa = format!("{:013X}", bits & ((1 << 52) - 1));
+            let mantissa = mantissa.trim_end_matches('0');
+            let (lead, exponent) = match (bits >> 52) as i32 {
+                0 => (0, -1022), // subnormal
+                biased => (1, biased - 1023),
+            };
+            let point = if mantissa.is_empty() { "" } else { "." };
+            format!("{}0x{}{}{}p{}", sign, lead, point, mantissa, exponent)
+        }
+        _ => x.to_string(),
+    }
+}
+
+impl RPNExpr {
+    // an expression that isn't a tree, like an empty one, prints as its
+    // rpn words
+    pub fn to_string_with(&self, opts: &PrintOptions) -> String {
+        match self.build_ast() {
+            Ok(ast) => walk(&Plain(opts), &ast, opts).0,
+            Err(_) => self.to_rpn_string(),
+        }
+    }
+}
+
+impl SpannedExpr {
+    pub fn to_string_with(&self, opts: &PrintOptions) -> String {
+        match (opts.parens, self.spans.len()) {
+            (Parens::Faithful, n) if n > 0 => self.text(n - 1),
+            _ => self.expr.to_string_with(opts),
         }
-        ops.pop().unwrap()
     }
 }
 
 impl fmt::Display for RPNExpr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fn printer(root: &AST) -> (String, (usize, Assoc)) {
-            match root {
-                AST::Leaf(ref token) => match *token {
-                    MathToken::Number(ref x) => (x.to_string(), precedence(token)),
-                    MathToken::Variable(ref x) => (x.to_string(), precedence(token)),
-                    _ => unreachable!(),
-                },
-                AST::Node(ref token, ref args) => {
-                    match *token {
-                        MathToken::UOp(ref op) => {
-                            let subtree = printer(&args[0]);
-                            let (prec, assoc) = precedence(token);
-                            // TODO: distinguish perfix/postfix operators
-                            if prec > (subtree.1).0 {
-                                (format!("{}({})", op, subtree.0), (prec, assoc))
-                            } else {
-                                (format!("{}{}", op, subtree.0), (prec, assoc))
-                            }
-                        }
-                        MathToken::BOp(ref op) => {
-                            let (lhs, rhs) = (printer(&args[0]), printer(&args[1]));
-                            let (prec, assoc) = precedence(token);
-
-                            let lh = if prec > (lhs.1).0
-                                || (prec == (lhs.1).0 && assoc != Assoc::Left)
-                            {
-                                format!("({})", lhs.0)
-                            } else {
-                                lhs.0
-                            };
-                            let rh = if prec > (rhs.1).0
-                                || (prec == (rhs.1).0 && assoc != Assoc::Right)
-                            {
-                                format!("({})", rhs.0)
-                            } else {
-                                rhs.0
-                            };
-                            // NOTE: '2+(3+4)' will show parens to indicate that user
-                            // explicitly put them there
-                            (format!("{} {} {}", lh, op, rh), (prec, assoc))
-                        }
-                        MathToken::Function(ref func, _) => {
-                            let expr = args
-                                .iter()
-                                .map(|leaf| printer(&leaf).0)
-                                .collect::<Vec<String>>()
-                                .join(", ");
-                            (format!("{}({})", func, expr), precedence(token))
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-            }
+        write!(f, "{}", self.to_string_with(&PrintOptions::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrintOptions;
+    use crate::parser::ShuntingParser;
+
+    #[test]
+    fn print_conditionals() {
+        let expr = ShuntingParser::parse_str("if(x<=1,1,x*(f(x-1)==2))").unwrap();
+        assert_eq!(expr.to_string(), "if(x <= 1, 1, x * (f(x - 1) == 2))");
+        let expr = ShuntingParser::parse_str("piecewise(x<0,-x,x)+1").unwrap();
+        assert_eq!(expr.to_string(), "piecewise(x < 0, -x, x) + 1");
+    }
+
+    #[test]
+    fn print_abs_bars() {
+        let expr = ShuntingParser::parse_str("||x|-1| * abs(-y)").unwrap();
+        assert_eq!(expr.to_string(), "abs(abs(x) - 1) * abs(-y)");
+        let opts = PrintOptions {
+            abs_bars: true,
+            ..PrintOptions::default()
+        };
+        assert_eq!(expr.to_string_with(&opts), "||x| - 1| * |-y|");
+    }
+
+    #[test]
+    fn print_radix() {
+        let expr = ShuntingParser::parse_str("0x1F + 0b1011 * 0o17 - 0x1.8p-3 / 1.5").unwrap();
+        assert_eq!(expr.to_string(), "0x1F + 0b1011 * 0o17 - 0x1.8p-3 / 1.5");
+        // hex floats are normalized, integral ones print as hex integers
+        let expr = ShuntingParser::parse_str("0x0.1 + 0x1.8p3 + 0xffffffff").unwrap();
+        assert_eq!(expr.to_string(), "0x1p-4 + 0xC + 0xFFFFFFFF");
+    }
+
+    #[test]
+    fn print_integer_ops() {
+        use crate::grammar::Grammar;
+        let src = "(~x & 0xFF | 0b1 << 4) xor 0xFFFF_FFFF_FFFF_FFFF";
+        let expr = ShuntingParser::parse_str_with(src, &Grammar::integer()).unwrap();
+        assert_eq!(
+            expr.to_string(),
+            "(~x & 0xFF | 0b1 << 4) xor 0xFFFFFFFFFFFFFFFF"
+        );
+    }
+
+    #[test]
+    fn print_units() {
+        use crate::grammar::Grammar;
+        let grammar = Grammar::units();
+        let cases = [
+            ("3 m / 2 s", "3 m / 2 s"),
+            ("5 kg*9.81 m/s^2 in N", "5 kg * 9.81 m/s^2 in N"),
+            ("(3 m)^2 + 1 m^2", "(3 m) ^ 2 + 1 m^2"),
+            ("-2 kg m", "-2 kg m"),
+            ("x * (1 + 2 h) in min", "x * (1 + 2 h) in min"),
+        ];
+        for (src, printed) in cases.iter() {
+            let expr = ShuntingParser::parse_str_with(src, &grammar).unwrap();
+            assert_eq!(expr.to_string(), *printed);
+            assert_eq!(ShuntingParser::parse_str_with(printed, &grammar), Ok(expr));
+        }
+    }
+
+    #[test]
+    fn print_postfix() {
+        let cases = [
+            ("7!", "7!"),
+            ("(x+1)!", "(x + 1)!"),
+            ("-x!", "-x!"),
+            ("(-x)!", "(-x)!"),
+            ("3! - 2", "3! - 2"),
+            ("2^3!", "2 ^ 3!"),
+            ("(2^3)!!", "(2 ^ 3)!!"),
+        ];
+        for (src, printed) in cases.iter() {
+            let expr = ShuntingParser::parse_str(src).unwrap();
+            assert_eq!(expr.to_string(), *printed);
+        }
+
+        // a postfix operator binding looser than '*' takes what came before
+        use crate::grammar::{Grammar, Operator};
+        let mut grammar = Grammar::default();
+        grammar.operators.add(Operator::postfix("°", 25));
+        let opts = PrintOptions {
+            operators: grammar.operators.clone(),
+            ..PrintOptions::default()
+        };
+        let expr = ShuntingParser::parse_str_with("2 * x° * 3", &grammar).unwrap();
+        assert_eq!(expr.to_string_with(&opts), "(2 * x°) * 3");
+        let expr = ShuntingParser::parse_str_with("2 * (x°)", &grammar).unwrap();
+        assert_eq!(expr.to_string_with(&opts), "2 * (x°)");
+    }
+
+    #[test]
+    fn print_custom_operators() {
+        // operators missing from the options are parenthesized, so they
+        // read back the same, with the table they print as usual
+        use crate::grammar::{Assoc, Grammar, Operator};
+        let mut grammar = Grammar::default();
+        grammar
+            .operators
+            .add(Operator::infix("<+>", 25, Assoc::Left));
+        let opts = PrintOptions {
+            operators: grammar.operators.clone(),
+            ..PrintOptions::default()
+        };
+        for src in ["(1 <+> 2) * 3", "1 <+> 2 * 3", "1 <+> 2 <+> 3"].iter() {
+            let expr = ShuntingParser::parse_str_with(src, &grammar).unwrap();
+            let printed = expr.to_string();
+            let reparsed = ShuntingParser::parse_str_with(&printed, &grammar).unwrap();
+            assert_eq!(reparsed, expr, "{}", printed);
+            assert_eq!(expr.to_string_with(&opts), *src);
+        }
+    }
+
+    use crate::grammar::Fixity::*;
+    use crate::parser::RPNExpr;
+    use crate::tokenizer::{MathToken, MathToken::*, Radix::*};
+    use proptest::prelude::*;
+
+    // random well formed expressions in rpn
+    fn arb_rpn() -> impl Strategy<Value = Vec<MathToken>> {
+        let leaf = prop_oneof![
+            (0u32..1000).prop_map(|n| vec![Number(n as f64, Dec)]),
+            (0.0..1e6f64).prop_map(|x| vec![Number(x, Dec)]),
+            "[a-z][a-z0-9]{0,2}".prop_map(|v| vec![Variable(v)]),
+        ];
+        let bops = vec!["+", "-", "*", "/", "%", "^", "<", "==", "&&"];
+        leaf.prop_recursive(6, 64, 3, move |inner| {
+            prop_oneof![
+                (
+                    inner.clone(),
+                    inner.clone(),
+                    prop::sample::select(bops.clone())
+                )
+                    .prop_map(|(l, r, op)| [l, r, vec![BOp(op.to_string())]].concat()),
+                (
+                    inner.clone(),
+                    prop::sample::select(vec![("-", Prefix), ("!", Postfix)])
+                )
+                    .prop_map(|(e, (op, fixity))| [
+                        e,
+                        vec![UOp(op.to_string(), fixity)]
+                    ]
+                    .concat()),
+                prop::collection::vec(inner, 1..4).prop_map(|args| {
+                    let n = args.len();
+                    [args.concat(), vec![Function("max".to_string(), n)]].concat()
+                }),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn print_round_trip(rpn in arb_rpn()) {
+            let expr = RPNExpr(rpn);
+            let printed = expr.to_string();
+            prop_assert_eq!(ShuntingParser::parse_str(&printed), Ok(expr), "{}", printed);
+        }
+    }
+
+    #[test]
+    fn print_parens() {
+        use super::Parens;
+        use crate::grammar::Grammar;
+        let minimal = PrintOptions {
+            parens: Parens::Minimal,
+            ..PrintOptions::default()
+        };
+        let cases = [
+            ("2+(3+4)", "2 + (3 + 4)", "2 + 3 + 4"),
+            ("a*(b/c)", "a * (b / c)", "a * b / c"),
+            (
+                "a+(b-c)-(d+e)",
+                "a + (b - c) - (d + e)",
+                "a + b - c - (d + e)",
+            ),
+            ("a/(b*c)", "a / (b * c)", "a / (b * c)"),
+            ("(2^3)^(4^5)", "(2 ^ 3) ^ 4 ^ 5", "(2 ^ 3) ^ 4 ^ 5"),
+        ];
+        for (src, precedence, min) in cases.iter() {
+            let expr = ShuntingParser::parse_str(src).unwrap();
+            assert_eq!(expr.to_string(), *precedence);
+            assert_eq!(expr.to_string_with(&minimal), *min);
         }
 
-        write!(f, "{}", printer(&self.build_ast()).0)
+        let faithful = PrintOptions {
+            parens: Parens::Faithful,
+            ..PrintOptions::default()
+        };
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            ..Grammar::default()
+        };
+        let src = "  2x (y+1 )+((z)) ";
+        let expr = ShuntingParser::parse_str_spanned(src, &grammar).unwrap();
+        assert_eq!(expr.to_string_with(&faithful), "2x (y+1 )+((z))");
+        assert_eq!(expr.to_string_with(&minimal), "2 * x * (y + 1) + z");
+        // without a source to be faithful to precedence decides
+        assert_eq!(expr.expr.to_string_with(&faithful), "2 * x * (y + 1) + z");
     }
 }
@@ -0,0 +1,259 @@
+use crate::grammar::{Fixity, Grammar, OperatorTable};
+use crate::parser::{quote_lazy_args, RPNExpr};
+use crate::rpnprint::{print_integer, print_number, AST};
+use crate::tokenizer::{MathToken, MathTokenizer};
+
+// Reading and writing expressions as space separated words in postfix (RPN)
+// or prefix (Polish) order, eg: '3 4 2 * +' or '+ 3 * 4 2'. Functions carry
+// their arity like 'max/2', a unary operator that's also binary is written
+// as '-/1' and units go in brackets like '[m/s]'.
+
+// the word for a token, operators are written as in infix
+fn word(token: &MathToken, table: &OperatorTable) -> String {
+    match *token {
+        MathToken::Number(x, radix) => print_number(x, radix),
+        MathToken::Integer(n, radix) => print_integer(n, radix),
+        MathToken::Variable(ref var) => var.to_string(),
+        MathToken::Function(ref name, arity) => format!("{}/{}", name, arity),
+        MathToken::BOp(ref op) => op.to_string(),
+        MathToken::UOp(ref op, _) if table.get(op, Fixity::Infix).is_some() => {
+            format!("{}/1", op)
+        }
+        MathToken::UOp(ref op, _) => op.to_string(),
+        MathToken::Unit(ref unit) => format!("[{}]", unit.name.replace(' ', "*")),
+        // not found in a parsed expression
+        _ => format!("{:?}", token),
+    }
+}
+
+// the token for a word, the reverse of 'word'
+fn token(word: &str, grammar: &Grammar) -> Result<MathToken, String> {
+    let bad_token = || Err(format!("Bad token: {}", word));
+    // units are in brackets, eg: '[kg*m/s^2]'
+    if let Some(unit) = word.strip_prefix('[').and_then(|w| w.strip_suffix(']')) {
+        return match grammar.units {
+            Some(ref units) => units.parse(unit).map(MathToken::Unit),
+            None => bad_token(),
+        };
+    }
+    let table = &grammar.operators;
+    let (prefix, postfix) = (Fixity::Prefix, Fixity::Postfix);
+    let is_operator = |name| {
+        [Fixity::Infix, prefix, postfix]
+            .iter()
+            .any(|&f| table.get(name, f).is_some())
+    };
+    let (name, arity) = match word.rsplit_once('/') {
+        // an operator like '//' is read whole before looking for an arity
+        _ if is_operator(word) => (word, None),
+        Some((name, n)) if !name.is_empty() => match n.parse::<usize>() {
+            Ok(arity) => (name, Some(arity)),
+            Err(_) => return bad_token(),
+        },
+        _ => (word, None),
+    };
+    match arity {
+        Some(2) | None if table.get(name, Fixity::Infix).is_some() => {
+            return Ok(MathToken::BOp(name.to_string()))
+        }
+        Some(1) | None if table.get(name, prefix).is_some() => {
+            return Ok(MathToken::UOp(name.to_string(), prefix))
+        }
+        Some(1) | None if table.get(name, postfix).is_some() => {
+            return Ok(MathToken::UOp(name.to_string(), postfix))
+        }
+        _ => (),
+    }
+    // anything else should read as a single number or name
+    let lex = MathTokenizer::with_grammar(name.chars(), grammar.clone());
+    match (lex.collect::<Vec<_>>().as_slice(), arity) {
+        ([MathToken::Number(x, radix)], None) => Ok(MathToken::Number(*x, *radix)),
+        ([MathToken::UOp(minus, _), MathToken::Number(x, radix)], None) if minus == "-" => {
+            Ok(MathToken::Number(-x, *radix))
+        }
+        ([MathToken::Integer(n, radix)], None) => Ok(MathToken::Integer(*n, *radix)),
+        ([MathToken::Variable(var)], None) => Ok(MathToken::Variable(var.to_string())),
+        ([MathToken::Variable(var)], Some(n)) => Ok(MathToken::Function(var.to_string(), n)),
+        _ => bad_token(),
+    }
+}
+
+// assemble tokens given in rpn order, or in polish order read backwards,
+// into an expression
+fn assemble<'a>(
+    words: impl Iterator<Item = &'a str>,
+    grammar: &Grammar,
+    polish: bool,
+) -> Result<RPNExpr, String> {
+    let mut operands: Vec<Vec<MathToken>> = Vec::new();
+    for word in words {
+        let token = token(word, grammar)?;
+        if token.arity() > operands.len() {
+            return Err(format!("Missing operands for {}", word));
+        }
+        let mut args = operands.split_off(operands.len() - token.arity());
+        if polish {
+            args.reverse(); // the first operand was the last one read
+        }
+        let mut subtree = args.concat();
+        subtree.push(token);
+        operands.push(subtree);
+    }
+    match operands.len() {
+        0 => Err("Empty expression".to_string()),
+        1 => Ok(RPNExpr(quote_lazy_args(operands.pop().unwrap()))),
+        _ => Err("Too many operands".to_string()),
+    }
+}
+
+fn polish(ast: &AST, table: &OperatorTable, out: &mut Vec<String>) {
+    match ast {
+        AST::Leaf(token) => out.push(word(token, table)),
+        AST::Node(token, args) => {
+            out.push(word(token, table));
+            for arg in args.iter() {
+                polish(arg, table, out);
+            }
+        }
+    }
+}
+
+impl RPNExpr {
+    pub fn from_rpn_str(rpn: &str) -> Result<RPNExpr, String> {
+        Self::from_rpn_str_with(rpn, &Grammar::default())
+    }
+
+    pub fn from_rpn_str_with(rpn: &str, grammar: &Grammar) -> Result<RPNExpr, String> {
+        assemble(rpn.split_whitespace(), grammar, false)
+    }
+
+    pub fn from_polish_str(polish: &str) -> Result<RPNExpr, String> {
+        Self::from_polish_str_with(polish, &Grammar::default())
+    }
+
+    pub fn from_polish_str_with(polish: &str, grammar: &Grammar) -> Result<RPNExpr, String> {
+        assemble(polish.split_whitespace().rev(), grammar, true)
+    }
+
+    pub fn to_rpn_string(&self) -> String {
+        let table = OperatorTable::integer();
+        let words = self.0.iter().filter(|t| !matches!(t, MathToken::Quote(_)));
+        words.map(|t| word(t, &table)).collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn to_polish_string(&self) -> Result<String, String> {
+        let mut words = Vec::new();
+        polish(&self.build_ast()?, &OperatorTable::integer(), &mut words);
+        Ok(words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{Assoc, Grammar, Operator};
+    use crate::parser::{RPNExpr, ShuntingParser};
+    use crate::tokenizer::{MathToken, Radix};
+
+    #[test]
+    fn read_rpn() {
+        let expect = ShuntingParser::parse_str("3 + 4 * 2").unwrap();
+        assert_eq!(RPNExpr::from_rpn_str("3 4 2 * +"), Ok(expect));
+        let expect = ShuntingParser::parse_str("max(-x, 2, -1.5)! - if(x, y, 0x1F)").unwrap();
+        let rpn = "x -/1 2 1.5 -/1 max/3 ! x y 0x1F if/3 -";
+        assert_eq!(RPNExpr::from_rpn_str(rpn), Ok(expect));
+        // a sign makes a negative number rather than a unary minus
+        let rpn = RPNExpr::from_rpn_str("-1.5 2 ^").unwrap();
+        assert_eq!(rpn.0[0], MathToken::Number(-1.5, Radix::Dec));
+        assert_eq!(rpn.to_rpn_string(), "-1.5 2 ^");
+        assert_eq!(rpn.to_string(), "(-1.5) ^ 2");
+        let infix = ShuntingParser::parse_str(&rpn.to_string()).unwrap();
+        assert_eq!(infix.to_rpn_string(), "1.5 -/1 2 ^");
+        let expect = ShuntingParser::parse_str("- 5 + 3").unwrap();
+        assert_eq!(RPNExpr::from_polish_str("+ -/1 5 3"), Ok(expect));
+        let expect = ShuntingParser::parse_str("5 - 3").unwrap();
+        assert_eq!(RPNExpr::from_polish_str("- 5 3"), Ok(expect));
+
+        let grammar = Grammar::integer();
+        let expect = ShuntingParser::parse_str_with("~a xor 0b11 << 2", &grammar).unwrap();
+        assert_eq!(
+            RPNExpr::from_rpn_str_with("a ~ 0b11 2 << xor", &grammar),
+            Ok(expect)
+        );
+
+        // operators may contain the '/' that comes before an arity
+        let mut grammar = Grammar::default();
+        grammar.operators.add(Operator::infix("//", 3, Assoc::Left));
+        grammar.operators.add(Operator::prefix("//", 4));
+        let expect = ShuntingParser::parse_str_with("//7 // 2", &grammar).unwrap();
+        assert_eq!(
+            RPNExpr::from_rpn_str_with("7 ///1 2 //", &grammar),
+            Ok(expect)
+        );
+    }
+
+    #[test]
+    fn read_errors() {
+        let err = |msg: &str| Err(msg.to_string());
+        assert_eq!(RPNExpr::from_rpn_str("3 +"), err("Missing operands for +"));
+        assert_eq!(
+            RPNExpr::from_rpn_str("1 2 max/3"),
+            err("Missing operands for max/3")
+        );
+        assert_eq!(RPNExpr::from_rpn_str("1 2"), err("Too many operands"));
+        assert_eq!(RPNExpr::from_rpn_str(" "), err("Empty expression"));
+        assert_eq!(RPNExpr::from_rpn_str("1 2 $"), err("Bad token: $"));
+        assert_eq!(RPNExpr::from_rpn_str("x 3/2"), err("Bad token: 3/2"));
+        assert_eq!(RPNExpr::from_rpn_str("x f/y"), err("Bad token: f/y"));
+        assert_eq!(
+            RPNExpr::from_polish_str("* 2"),
+            err("Missing operands for *")
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let cases = [
+            (
+                "3+4*2/-(1-5)^2^3",
+                "3 4 2 * 1 5 - 2 3 ^ ^ -/1 / +",
+                "+ 3 / * 4 2 -/1 ^ - 1 5 ^ 2 3",
+            ),
+            ("f() * 7!", "f/0 7 ! *", "* f/0 ! 7"),
+            (
+                "piecewise(x < 0, -x, x)",
+                "x 0 < x -/1 x piecewise/3",
+                "piecewise/3 < x 0 -/1 x x",
+            ),
+            ("1.5e-3 + 0xFF", "0.0015 0xFF +", "+ 0.0015 0xFF"),
+            (
+                "sum(i^2, i, 1, n)",
+                "i 2 ^ i 1 n sum/4",
+                "sum/4 ^ i 2 i 1 n",
+            ),
+        ];
+        for (infix, rpn, polish) in cases.iter() {
+            let expr = ShuntingParser::parse_str(infix).unwrap();
+            assert_eq!(expr.to_rpn_string(), *rpn);
+            assert_eq!(expr.to_polish_string().as_deref(), Ok(*polish));
+            assert_eq!(RPNExpr::from_rpn_str(rpn).as_ref(), Ok(&expr));
+            assert_eq!(RPNExpr::from_polish_str(polish).as_ref(), Ok(&expr));
+        }
+    }
+
+    #[test]
+    fn units() {
+        let grammar = Grammar::units();
+        let expr = ShuntingParser::parse_str_with("5 kg * 9.81 m/s^2 in N", &grammar).unwrap();
+        let rpn = "5 [kg] * 9.81 [m/s^2] * * [N] in";
+        assert_eq!(expr.to_rpn_string(), rpn);
+        assert_eq!(RPNExpr::from_rpn_str_with(rpn, &grammar), Ok(expr));
+        let expr = RPNExpr::from_rpn_str_with("2 [kg*m] *", &grammar).unwrap();
+        assert_eq!(expr.to_string(), "2 kg m");
+        assert_eq!(
+            RPNExpr::from_rpn_str("2 [kg] *"),
+            Err("Bad token: [kg]".to_string())
+        );
+        let error = RPNExpr::from_rpn_str_with("2 [kg*furlong] *", &grammar);
+        assert_eq!(error, Err("Unknown unit: furlong".to_string()));
+    }
+}
@@ -1,5 +1,11 @@
 static WHITE: &[char] = &[' ', '\n', '\r', '\t'];
 static DIGITS: &[char] = &['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+static BIN_DIGITS: &[char] = &['0', '1'];
+static OCT_DIGITS: &[char] = &['0', '1', '2', '3', '4', '5', '6', '7'];
+static HEX_DIGITS: &[char] = &[
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'A', 'B', 'C',
+    'D', 'E', 'F',
+];
 static ALPHA: &[char] = &[
     '_', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r',
     's', 't', 'u', 'v', 'w', 'x', 'y', 'z', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K',
@@ -19,6 +25,7 @@ where
     src: I,
     buf: Vec<I::Item>,
     pos: isize,
+    extracted: usize, // how many items were extracted so far
 }
 
 // Scanners are Iterators
@@ -51,19 +58,36 @@ impl<I: Iterator<Item = char>> Scanner<I> {
         Some(self.extract_string())
     }
 
+    // skip digits allowing '_' separators between them, eg: 1_000_000
+    fn skip_digits(&mut self, digits: &[char]) -> bool {
+        if !self.skip_all(digits) {
+            return false;
+        }
+        loop {
+            let backtrack = self.buffer_pos();
+            if self.accept(&'_').is_none() || !self.skip_all(digits) {
+                self.set_buffer_pos(backtrack);
+                return true;
+            }
+        }
+    }
+
     // scan numbers like -?[0-9]+(\.[0-9]+)?([eE][+-][0-9]+)?
     pub fn scan_number(&mut self) -> Option<String> {
         let backtrack = self.buffer_pos();
         // optional sign
         self.accept_any(&['+', '-']);
+        if self.skip_radix_number() {
+            return Some(self.extract_string());
+        }
         // require integer part
-        if !self.skip_all(DIGITS) {
+        if !self.skip_digits(DIGITS) {
             self.set_buffer_pos(backtrack);
             return None;
         }
         // check for fractional part, else it's just an integer
         let backtrack = self.buffer_pos();
-        if self.accept(&'.').is_some() && !self.skip_all(DIGITS) {
+        if self.accept(&'.').is_some() && !self.skip_digits(DIGITS) {
             self.set_buffer_pos(backtrack);
             return Some(self.extract_string()); // integer
         }
@@ -71,7 +95,7 @@ impl<I: Iterator<Item = char>> Scanner<I> {
         let backtrack = self.buffer_pos();
         if self.accept_any(&['e', 'E']).is_some() {
             self.accept_any(&['+', '-']); // exponent sign is optional
-            if !self.skip_all(DIGITS) {
+            if !self.skip_digits(DIGITS) {
                 self.set_buffer_pos(backtrack);
                 return Some(self.extract_string()); //float
             }
@@ -80,21 +104,124 @@ impl<I: Iterator<Item = char>> Scanner<I> {
         Some(self.extract_string())
     }
 
-    pub fn scan_math_op(&mut self) -> Option<String> {
-        const OPS: &[char] = &['+', '-', '*', '/', '%', '^', '!', '(', ')', ','];
-        if self.accept_any(&['>', '=', '<']).is_some() {
-            // accept '<', '>', '=', '<=', '>=', '=='
-            self.accept(&'=');
-            Some(self.extract_string())
-        } else if self.accept(&'*').is_some() {
-            // accept '*', '**'
-            self.accept(&'*');
-            Some(self.extract_string())
-        } else if self.accept_any(OPS).is_some() {
-            Some(self.extract_string())
-        } else {
-            None
+    // skip numbers like 0b[01]+, 0o[0-7]+ or 0x[0-9a-f]+(\.[0-9a-f]+)?([pP][+-]?[0-9]+)?
+    fn skip_radix_number(&mut self) -> bool {
+        let backtrack = self.buffer_pos();
+        let digits = match (self.accept(&'0'), self.accept_any(&['b', 'o', 'x'])) {
+            (Some(_), Some('b')) => BIN_DIGITS,
+            (Some(_), Some('o')) => OCT_DIGITS,
+            (Some(_), Some('x')) => HEX_DIGITS,
+            _ => &[],
+        };
+        if !self.skip_digits(digits) {
+            self.set_buffer_pos(backtrack);
+            return false;
         }
+        if digits == HEX_DIGITS {
+            // check for fractional part in hex floats
+            let backtrack = self.buffer_pos();
+            if self.accept(&'.').is_some() && !self.skip_digits(HEX_DIGITS) {
+                self.set_buffer_pos(backtrack);
+                return true;
+            }
+            // check for binary exponent part
+            let backtrack = self.buffer_pos();
+            if self.accept_any(&['p', 'P']).is_some() {
+                self.accept_any(&['+', '-']);
+                if !self.skip_digits(DIGITS) {
+                    self.set_buffer_pos(backtrack);
+                }
+            }
+        }
+        true
+    }
+
+    // scan the longest of the operator 'symbols' or punctuation, eg: '<='
+    // rather than '<' followed by '='
+    pub fn scan_math_op(&mut self, symbols: &[&str]) -> Option<String> {
+        const PUNCTUATION: &[&str] = &["(", ")", ",", "|", "="];
+        let start = self.buffer_pos();
+        let mut longest = None;
+        for symbol in symbols.iter().chain(PUNCTUATION).filter(|s| !s.is_empty()) {
+            if symbol.chars().all(|c| self.accept(&c).is_some()) {
+                longest = longest.max(Some(self.buffer_pos()));
+            }
+            self.set_buffer_pos(start);
+        }
+        self.set_buffer_pos(longest?);
+        Some(self.extract_string())
+    }
+
+    // scan one of the single char 'suffixes' unless more identifier chars
+    // follow, eg: the 'k' in '4.7k' but not in '4.7kg'
+    pub fn scan_suffix(&mut self, suffixes: &[char]) -> Option<char> {
+        let backtrack = self.buffer_pos();
+        let suffix = self.accept_any(suffixes)?;
+        if matches!(self.peek(), Some(next) if ALNUM.contains(&next)) {
+            self.set_buffer_pos(backtrack);
+            return None;
+        }
+        self.extract();
+        Some(suffix)
+    }
+
+    // scan units like 'kg m/s^2' where every name is one 'is_unit' knows,
+    // names can be raised to integer powers and are separated by a space or
+    // divided by a '/' with no spaces around it
+    pub fn scan_unit(&mut self, is_unit: impl Fn(&str) -> bool) -> Option<String> {
+        let mut unit = String::new();
+        loop {
+            let backtrack = self.buffer_pos();
+            let separator = if unit.is_empty() {
+                ""
+            } else if self.accept(&'/').is_some() {
+                "/"
+            } else if self.skip_all(WHITE) {
+                " "
+            } else {
+                break;
+            };
+            match self.scan_unit_factor(&is_unit) {
+                Some(factor) => unit = unit + separator + &factor,
+                None => {
+                    self.set_buffer_pos(backtrack);
+                    break;
+                }
+            }
+        }
+        if unit.is_empty() {
+            return None;
+        }
+        self.extract();
+        Some(unit)
+    }
+
+    fn scan_unit_factor(&mut self, is_unit: impl Fn(&str) -> bool) -> Option<String> {
+        let backtrack = self.buffer_pos();
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_alphabetic()) {
+            self.next();
+            name.push(c);
+        }
+        // a name followed by '(' is a function
+        if !is_unit(&name) || self.peek() == Some('(') {
+            self.set_buffer_pos(backtrack);
+            return None;
+        }
+        let power = self.buffer_pos();
+        if self.accept(&'^').is_some() {
+            let negative = self.accept(&'-').is_some();
+            let start = self.buffer_pos();
+            if self.skip_all(DIGITS) {
+                let digits: String = self.buf[start as usize + 1..=self.pos as usize]
+                    .iter()
+                    .collect();
+                name = format!("{}^{}{}", name, if negative { "-" } else { "" }, digits);
+            } else {
+                self.set_buffer_pos(power);
+            }
+        }
+        Some(name)
     }
 
     // scan [a-zA-Z_][a-zA-Z0-9_]+
@@ -115,6 +242,7 @@ where
             src: source,
             buf: Vec::new(),
             pos: -1,
+            extracted: 0,
         }
     }
 
@@ -150,6 +278,11 @@ where
         peeked
     }
 
+    // Offset of the next item to extract from the start of the source
+    pub fn extracted(&self) -> usize {
+        self.extracted
+    }
+
     // Consumes the buffer into a new token (which can be ignored)
     pub fn extract(&mut self) -> Vec<I::Item> {
         // Check where to shift buffer
@@ -160,6 +293,7 @@ where
         // Split buffer and keep the remainder
         let mut remaining = self.buf.split_off(split_point as usize);
         std::mem::swap(&mut self.buf, &mut remaining);
+        self.extracted += remaining.len();
         remaining
     }
 }
@@ -0,0 +1,409 @@
+use crate::grammar::Grammar;
+use crate::parser::{RPNExpr, ShuntingParser};
+use crate::rpneval::MathContext;
+use crate::tokenizer::{MathToken, MathTokenizer, Radix};
+use std::collections::{BTreeMap, BTreeSet};
+
+// A sheet of named formulas like 'tax = total * rate' that may refer to each
+// other. Cells are kept evaluated: changing one recalculates just the cells
+// that depend on it, in dependency order.
+
+#[derive(Debug, Clone)]
+struct Cell {
+    formula: RPNExpr,
+    deps: BTreeSet<String>,
+    value: Result<f64, String>,
+    // the context's variable of the same name, back while the cell fails
+    // and for good once it's removed
+    shadowed: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sheet {
+    // functions and constants for the formulas, cell values are kept in its
+    // variables so they shadow any of the same name
+    context: MathContext,
+    cells: BTreeMap<String, Cell>,
+    grammar: Grammar,
+}
+
+impl Sheet {
+    pub fn new() -> Sheet {
+        Sheet::default()
+    }
+
+    pub fn with_context(context: MathContext, grammar: Grammar) -> Sheet {
+        Sheet {
+            context,
+            cells: BTreeMap::new(),
+            grammar,
+        }
+    }
+
+    // enter a line like 'total = a + b'
+    pub fn enter(&mut self, line: &str) -> Result<Vec<String>, String> {
+        let mut lex = MathTokenizer::with_grammar(line.chars(), self.grammar.clone());
+        let name = match lex.next() {
+            Some(MathToken::Variable(name)) => name,
+            _ => return Err("Expected cell name".to_string()),
+        };
+        if lex.next() != Some(MathToken::BOp("=".to_string())) {
+            return Err("Expected '=' after cell name".to_string());
+        }
+        let formula = ShuntingParser::parse_with(&mut lex, &self.grammar)?;
+        if formula.0.is_empty() {
+            return Err("Empty formula".to_string());
+        }
+        self.set(&name, formula)
+    }
+
+    pub fn set_value(&mut self, name: &str, value: f64) -> Result<Vec<String>, String> {
+        self.set(name, RPNExpr(vec![MathToken::Number(value, Radix::Dec)]))
+    }
+
+    // set the formula of a cell, returns the cells recalculated in the order
+    // they were evaluated, a formula that makes a cycle is refused
+    pub fn set(&mut self, name: &str, formula: RPNExpr) -> Result<Vec<String>, String> {
+        let deps = self.dependencies(&formula);
+        if let Some(cycle) = self.find_cycle(name, &deps) {
+            return Err(format!("Cycle: {}", cycle.join(" -> ")));
+        }
+        let shadowed = match self.cells.get(name) {
+            Some(cell) => cell.shadowed,
+            None => self.context.vars.get(name).copied(),
+        };
+        let cell = Cell {
+            formula,
+            deps,
+            value: Err("Not calculated".to_string()),
+            shadowed,
+        };
+        self.cells.insert(name.to_string(), cell);
+        Ok(self.recalculate_from(name))
+    }
+
+    // remove a cell, returns the cells recalculated without it
+    pub fn remove(&mut self, name: &str) -> Option<Vec<String>> {
+        let cell = self.cells.remove(name)?;
+        self.unshadow(name, cell.shadowed);
+        let mut recalculated = self.recalculate_from(name);
+        recalculated.retain(|cell| cell != name);
+        Some(recalculated)
+    }
+
+    // define a function for the formulas, everything is recalculated, a
+    // function that makes a cycle through the cells calling it is refused
+    pub fn define(&mut self, def: &str) -> Result<Vec<String>, String> {
+        let def = ShuntingParser::parse_definition(def)?;
+        let fname = def.name.clone();
+        let previous = self.context.funcs.get(&fname).cloned();
+        self.context.setfn(def)?;
+        let names: BTreeSet<_> = self.cells.keys().cloned().collect();
+        let mut previous_deps = Vec::new();
+        for name in names.iter() {
+            let deps = self.dependencies(&self.cells[name].formula);
+            let cell = self.cells.get_mut(name).unwrap();
+            previous_deps.push(std::mem::replace(&mut cell.deps, deps));
+        }
+        for name in names.iter() {
+            if let Some(cycle) = self.find_cycle(name, &self.cells[name].deps) {
+                for (name, deps) in names.iter().zip(previous_deps) {
+                    self.cells.get_mut(name).unwrap().deps = deps;
+                }
+                match previous {
+                    Some(def) => self.context.funcs.insert(fname, def),
+                    None => self.context.funcs.remove(&fname),
+                };
+                return Err(format!("Cycle: {}", cycle.join(" -> ")));
+            }
+        }
+        Ok(self.recalculate(&names))
+    }
+
+    pub fn value(&self, name: &str) -> Result<f64, String> {
+        match self.cells.get(name) {
+            Some(cell) => cell.value.clone(),
+            None => Err(format!("Unknown cell: {}", name)),
+        }
+    }
+
+    pub fn formula(&self, name: &str) -> Option<&RPNExpr> {
+        self.cells.get(name).map(|cell| &cell.formula)
+    }
+
+    // the cells whose formulas refer to 'name'
+    pub fn dependents(&self, name: &str) -> Vec<String> {
+        let cells = self.cells.iter();
+        let dependents = cells.filter(|(_, cell)| cell.deps.contains(name));
+        dependents.map(|(dependent, _)| dependent.clone()).collect()
+    }
+
+    // all cells, each one after the cells it depends on
+    pub fn order(&self) -> Vec<String> {
+        let names = self.cells.keys().cloned().collect();
+        self.sort(&names)
+    }
+
+    // the variables 'formula' reads, including those read by the user
+    // functions it calls, directly or not
+    fn dependencies(&self, formula: &RPNExpr) -> BTreeSet<String> {
+        let mut deps = formula.variables();
+        let mut pending = self.calls(formula);
+        let mut visited = BTreeSet::new();
+        while let Some(fname) = pending.pop() {
+            if !visited.insert(fname.clone()) {
+                continue;
+            }
+            if let Some(def) = self.context.funcs.get(&fname) {
+                let globals = def.body.variables().into_iter();
+                deps.extend(globals.filter(|var| !def.params.contains(var)));
+                pending.extend(self.calls(&def.body));
+            }
+        }
+        deps
+    }
+
+    // the functions 'rpn' calls, including those operators are mapped to
+    fn calls(&self, rpn: &RPNExpr) -> Vec<String> {
+        let calls = rpn.0.iter().filter_map(|token| match *token {
+            MathToken::Function(ref fname, _) => Some(fname),
+            MathToken::BOp(ref op) | MathToken::UOp(ref op, _) => self.context.ops.get(op),
+            _ => None,
+        });
+        calls.cloned().collect()
+    }
+
+    // put back the context's variable a cell named 'name' shadowed
+    fn unshadow(&mut self, name: &str, shadowed: Option<f64>) {
+        match shadowed {
+            Some(x) => self.context.setvar(name, x),
+            None => {
+                self.context.vars.remove(name);
+            }
+        }
+    }
+
+    // a path of dependencies leading from 'deps' back to 'name', as it would
+    // be if 'name' depended on 'deps'
+    fn find_cycle(&self, name: &str, deps: &BTreeSet<String>) -> Option<Vec<String>> {
+        let mut path = vec![name.to_string()];
+        let mut visited = BTreeSet::new();
+        for dep in deps {
+            if self.reaches(dep, name, &mut path, &mut visited) {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    fn reaches(
+        &self,
+        from: &str,
+        to: &str,
+        path: &mut Vec<String>,
+        visited: &mut BTreeSet<String>,
+    ) -> bool {
+        path.push(from.to_string());
+        if from == to {
+            return true;
+        }
+        if visited.insert(from.to_string()) {
+            if let Some(cell) = self.cells.get(from) {
+                for dep in cell.deps.iter() {
+                    if self.reaches(dep, to, path, visited) {
+                        return true;
+                    }
+                }
+            }
+        }
+        path.pop();
+        false
+    }
+
+    // 'name' and everything that depends on it, directly or not
+    fn recalculate_from(&mut self, name: &str) -> Vec<String> {
+        let mut affected = BTreeSet::new();
+        let mut pending = vec![name.to_string()];
+        while let Some(name) = pending.pop() {
+            if affected.insert(name.clone()) {
+                pending.extend(self.dependents(&name));
+            }
+        }
+        affected.retain(|name| self.cells.contains_key(name));
+        self.recalculate(&affected)
+    }
+
+    fn recalculate(&mut self, names: &BTreeSet<String>) -> Vec<String> {
+        let order = self.sort(names);
+        for name in order.iter() {
+            let value = self.evaluate(&self.cells[name]);
+            match value {
+                Ok(x) => self.context.setvar(name, x),
+                Err(_) => self.unshadow(name, self.cells[name].shadowed),
+            }
+            self.cells.get_mut(name).unwrap().value = value;
+        }
+        order
+    }
+
+    // a failing cell fails the cells that depend on it the same way
+    fn evaluate(&self, cell: &Cell) -> Result<f64, String> {
+        for dep in cell.deps.iter() {
+            if let Some(Cell { value: Err(e), .. }) = self.cells.get(dep) {
+                return Err(e.clone());
+            }
+        }
+        self.context.eval(&cell.formula)
+    }
+
+    // depth first topological sort of 'names', sets keep it deterministic
+    fn sort(&self, names: &BTreeSet<String>) -> Vec<String> {
+        fn visit(sheet: &Sheet, name: &str, names: &BTreeSet<String>, order: &mut Vec<String>) {
+            if order.iter().any(|done| done == name) {
+                return;
+            }
+            for dep in sheet.cells[name].deps.iter() {
+                if names.contains(dep) {
+                    visit(sheet, dep, names, order);
+                }
+            }
+            order.push(name.to_string());
+        }
+        let mut order = Vec::new();
+        for name in names {
+            visit(self, name, names, &mut order);
+        }
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recalculation() {
+        let mut sheet = Sheet::new();
+        sheet.enter("tax = total * rate").unwrap();
+        assert_eq!(
+            sheet.value("tax"),
+            Err("Unknown Variable: total".to_string())
+        );
+        assert_eq!(
+            sheet.enter("total = a + b"),
+            Ok(vec!["total".to_string(), "tax".to_string()])
+        );
+        sheet.set_value("a", 100.0).unwrap();
+        sheet.set_value("b", 20.0).unwrap();
+        sheet.set_value("rate", 0.25).unwrap();
+        sheet.enter("label = 2 * pi").unwrap();
+        assert_eq!(sheet.value("tax"), Ok(30.0));
+        assert_eq!(sheet.order(), ["a", "b", "label", "rate", "total", "tax"]);
+
+        // only what depends on b is recalculated, in dependency order
+        assert_eq!(
+            sheet.set_value("b", 60.0),
+            Ok(vec![
+                "b".to_string(),
+                "total".to_string(),
+                "tax".to_string()
+            ])
+        );
+        assert_eq!(sheet.value("tax"), Ok(40.0));
+        assert_eq!(
+            sheet.set_value("rate", 0.5),
+            Ok(vec!["rate".to_string(), "tax".to_string()])
+        );
+        assert_eq!(sheet.value("tax"), Ok(80.0));
+        assert_eq!(sheet.dependents("total"), ["tax"]);
+
+        // errors flow to dependents until fixed
+        assert_eq!(
+            sheet.remove("a"),
+            Some(vec!["total".to_string(), "tax".to_string()])
+        );
+        assert_eq!(sheet.value("tax"), Err("Unknown Variable: a".to_string()));
+        sheet.enter("a = b / 2").unwrap();
+        assert_eq!(sheet.value("tax"), Ok(45.0));
+        assert_eq!(sheet.value("nope"), Err("Unknown cell: nope".to_string()));
+    }
+
+    #[test]
+    fn cycles() {
+        let mut sheet = Sheet::new();
+        sheet.enter("a = b + 1").unwrap();
+        sheet.enter("b = c * 2").unwrap();
+        sheet.enter("c = 1").unwrap();
+        assert_eq!(
+            sheet.enter("c = a - 1"),
+            Err("Cycle: c -> a -> b -> c".to_string())
+        );
+        assert_eq!(sheet.enter("d = d"), Err("Cycle: d -> d".to_string()));
+        // the refused formula changes nothing
+        assert_eq!(sheet.value("a"), Ok(3.0));
+        assert_eq!(
+            sheet.formula("c"),
+            Some(&RPNExpr(vec![MathToken::Number(1.0, Radix::Dec)]))
+        );
+        assert_eq!(
+            sheet.enter("a + 1"),
+            Err("Expected '=' after cell name".to_string())
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let mut sheet = Sheet::new();
+        sheet.enter("y = f(x)").unwrap();
+        sheet.set_value("x", 3.0).unwrap();
+        assert_eq!(sheet.value("y"), Err("Unknown function: f".to_string()));
+        sheet.define("f(t) = t^2").unwrap();
+        assert_eq!(sheet.value("y"), Ok(9.0));
+
+        // cells depend on the cells read by the functions they call
+        sheet.set_value("rate", 2.0).unwrap();
+        sheet.define("g(t) = t * rate").unwrap();
+        sheet.define("h(t) = g(t) + 1").unwrap();
+        sheet.enter("z = h(x)").unwrap();
+        assert_eq!(sheet.value("z"), Ok(7.0));
+        assert_eq!(
+            sheet.set_value("rate", 3.0),
+            Ok(vec!["rate".to_string(), "z".to_string()])
+        );
+        assert_eq!(sheet.value("z"), Ok(10.0));
+        assert_eq!(
+            sheet.enter("rate = z"),
+            Err("Cycle: rate -> z -> rate".to_string())
+        );
+        assert_eq!(
+            sheet.define("f(t) = t * y"),
+            Err("Cycle: y -> y".to_string())
+        );
+        // the refused function is left as it was
+        sheet.set_value("x", 4.0).unwrap();
+        assert_eq!(sheet.value("y"), Ok(16.0));
+    }
+
+    #[test]
+    fn shadowing() {
+        let mut sheet = Sheet::new();
+        sheet.enter("area = pi * r^2").unwrap();
+        sheet.set_value("r", 1.0).unwrap();
+        sheet.set_value("pi", 3.0).unwrap();
+        assert_eq!(sheet.value("area"), Ok(3.0));
+        // a failing cell or a removed one leaves the constant as it was
+        sheet.enter("pi = nope").unwrap();
+        assert_eq!(
+            sheet.value("area"),
+            Err("Unknown Variable: nope".to_string())
+        );
+        sheet.enter("tau = 2 * pi").unwrap();
+        assert_eq!(
+            sheet.value("tau"),
+            Err("Unknown Variable: nope".to_string())
+        );
+        sheet.remove("pi").unwrap();
+        assert_eq!(sheet.value("area"), Ok(std::f64::consts::PI));
+        assert_eq!(sheet.value("tau"), Ok(std::f64::consts::TAU));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::parser::RPNExpr;
+use crate::rpneval::MathContext;
+use std::fmt;
+
+// Numeric root finding, solves 'expr = 0' for one variable by evaluating
+// 'expr' over and over with the variable set to each guess. Equations like
+// 'x^3 - 2x = 5' are solved as 'x^3 - 2x - 5', see
+// ShuntingParser::parse_equation.
+
+const MAX_ITERATIONS: usize = 200;
+const MAX_NEWTON_ITERATIONS: usize = 100;
+const TOLERANCE: f64 = 1e-12;
+
+// where to start looking, an interval known to hold a root or a single guess
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Start {
+    Bracket(f64, f64),
+    Guess(f64),
+}
+
+impl From<(f64, f64)> for Start {
+    fn from((a, b): (f64, f64)) -> Start {
+        Start::Bracket(a, b)
+    }
+}
+
+impl From<f64> for Start {
+    fn from(x: f64) -> Start {
+        Start::Guess(x)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Method {
+    // halves the bracket until it's small enough, slow but sure
+    Bisection,
+    // bisection sped up with secant and inverse quadratic steps
+    Brent,
+    // follows the tangent from a guess, a bracket starts at its middle
+    Newton(Derivative),
+}
+
+// how Newton's method gets the slope
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Derivative {
+    // differentiate the expression once, then evaluate that
+    Symbolic,
+    // evaluate with dual numbers, works for user functions too
+    Dual,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum SolveError {
+    // evaluating the expression failed, or gave NaN
+    Eval(String),
+    // the expression has the same sign at both ends of the bracket
+    NoSignChange { a: f64, b: f64 },
+    // gave up after 'iterations' with 'x' the last estimate
+    NoConvergence { iterations: usize, x: f64 },
+    // Newton's method hit a flat spot
+    ZeroDerivative { x: f64 },
+    // the method only works from a bracket
+    NeedsBracket,
+}
+
+impl fmt::Display for SolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SolveError::Eval(ref error) => write!(f, "{}", error),
+            SolveError::NoSignChange { a, b } => {
+                write!(f, "No sign change between {} and {}", a, b)
+            }
+            SolveError::NoConvergence { iterations, x } => {
+                write!(
+                    f,
+                    "No convergence after {} iterations, at {}",
+                    iterations, x
+                )
+            }
+            SolveError::ZeroDerivative { x } => write!(f, "Zero derivative at {}", x),
+            SolveError::NeedsBracket => write!(f, "Method needs a bracket"),
+        }
+    }
+}
+
+impl From<String> for SolveError {
+    fn from(error: String) -> SolveError {
+        SolveError::Eval(error)
+    }
+}
+
+impl MathContext {
+    // find 'var' where 'expr' is zero, with Brent's method from a bracket or
+    // Newton's method from a guess
+    pub fn solve(
+        &self,
+        expr: &RPNExpr,
+        var: &str,
+        start: impl Into<Start>,
+    ) -> Result<f64, SolveError> {
+        let start = start.into();
+        let method = match start {
+            Start::Bracket(..) => Method::Brent,
+            Start::Guess(_) => Method::Newton(Derivative::Dual),
+        };
+        self.solve_with(expr, var, start, method)
+    }
+
+    pub fn solve_with(
+        &self,
+        expr: &RPNExpr,
+        var: &str,
+        start: impl Into<Start>,
+        method: Method,
+    ) -> Result<f64, SolveError> {
+        let mut cx = self.clone();
+        match (method, start.into()) {
+            (Method::Newton(derivative), start) => {
+                let x = match start {
+                    Start::Bracket(a, b) => (a + b) / 2.0,
+                    Start::Guess(x) => x,
+                };
+                cx.newton(expr, var, x, derivative)
+            }
+            (_, Start::Guess(_)) => Err(SolveError::NeedsBracket),
+            (Method::Bisection, Start::Bracket(a, b)) => cx.bisection(expr, var, a, b),
+            (Method::Brent, Start::Bracket(a, b)) => cx.brent(expr, var, a, b),
+        }
+    }
+
+    fn sample(&mut self, expr: &RPNExpr, var: &str, x: f64) -> Result<f64, SolveError> {
+        self.setvar(var, x);
+        match self.eval(expr)? {
+            y if y.is_nan() => Err(SolveError::Eval(format!("Not a number at {} = {}", var, x))),
+            y => Ok(y),
+        }
+    }
+
+    fn bisection(&mut self, expr: &RPNExpr, var: &str, a: f64, b: f64) -> Result<f64, SolveError> {
+        let (mut a, mut b) = (a, b);
+        let fa = self.sample(expr, var, a)?;
+        let fb = self.sample(expr, var, b)?;
+        if fa == 0.0 {
+            return Ok(a);
+        } else if fb == 0.0 {
+            return Ok(b);
+        } else if fa.signum() == fb.signum() {
+            return Err(SolveError::NoSignChange { a, b });
+        }
+        for _ in 0..MAX_ITERATIONS {
+            let mid = (a + b) / 2.0;
+            let fmid = self.sample(expr, var, mid)?;
+            if fmid == 0.0 || (b - a).abs() / 2.0 <= TOLERANCE + f64::EPSILON * mid.abs() {
+                return Ok(mid);
+            }
+            if fmid.signum() == fa.signum() {
+                a = mid;
+            } else {
+                b = mid;
+            }
+        }
+        Err(SolveError::NoConvergence {
+            iterations: MAX_ITERATIONS,
+            x: (a + b) / 2.0,
+        })
+    }
+
+    // Brent's method as laid out in Numerical Recipes: 'b' is the best
+    // estimate, the root stays between 'b' and 'c' and 'a' is the previous
+    // estimate
+    fn brent(&mut self, expr: &RPNExpr, var: &str, a: f64, b: f64) -> Result<f64, SolveError> {
+        let (mut a, mut b) = (a, b);
+        let mut fa = self.sample(expr, var, a)?;
+        let mut fb = self.sample(expr, var, b)?;
+        if fa == 0.0 {
+            return Ok(a);
+        } else if fb == 0.0 {
+            return Ok(b);
+        } else if fa.signum() == fb.signum() {
+            return Err(SolveError::NoSignChange { a, b });
+        }
+        let (mut c, mut fc) = (b, fb);
+        let mut step = b - a;
+        let mut last_step = step;
+        for _ in 0..MAX_ITERATIONS {
+            if fb.signum() == fc.signum() {
+                c = a;
+                fc = fa;
+                step = b - a;
+                last_step = step;
+            }
+            if fc.abs() < fb.abs() {
+                a = b;
+                b = c;
+                c = a;
+                fa = fb;
+                fb = fc;
+                fc = fa;
+            }
+            let tol = 2.0 * f64::EPSILON * b.abs() + TOLERANCE / 2.0;
+            let mid = (c - b) / 2.0;
+            if mid.abs() <= tol || fb == 0.0 {
+                return Ok(b);
+            }
+            if last_step.abs() >= tol && fa.abs() > fb.abs() {
+                // try interpolating, secant through two points or inverse
+                // quadratic through three
+                let s = fb / fa;
+                let (mut p, mut q) = if a == c {
+                    (2.0 * mid * s, 1.0 - s)
+                } else {
+                    let q = fa / fc;
+                    let r = fb / fc;
+                    let p = s * (2.0 * mid * q * (q - r) - (b - a) * (r - 1.0));
+                    (p, (q - 1.0) * (r - 1.0) * (s - 1.0))
+                };
+                if p > 0.0 {
+                    q = -q;
+                }
+                p = p.abs();
+                let min = (3.0 * mid * q - (tol * q).abs()).min((last_step * q).abs());
+                if 2.0 * p < min {
+                    last_step = step;
+                    step = p / q;
+                } else {
+                    step = mid;
+                    last_step = step;
+                }
+            } else {
+                step = mid;
+                last_step = step;
+            }
+            a = b;
+            fa = fb;
+            b += if step.abs() > tol {
+                step
+            } else {
+                tol.copysign(mid)
+            };
+            fb = self.sample(expr, var, b)?;
+        }
+        Err(SolveError::NoConvergence {
+            iterations: MAX_ITERATIONS,
+            x: b,
+        })
+    }
+
+    fn newton(
+        &mut self,
+        expr: &RPNExpr,
+        var: &str,
+        x: f64,
+        derivative: Derivative,
+    ) -> Result<f64, SolveError> {
+        let slope = match derivative {
+            Derivative::Symbolic => Some(expr.derivative(var)?),
+            Derivative::Dual => None,
+        };
+        let mut x = x;
+        for _ in 0..MAX_NEWTON_ITERATIONS {
+            let (y, dy) = match slope {
+                Some(ref slope) => {
+                    let y = self.sample(expr, var, x)?;
+                    (y, self.eval(slope)?)
+                }
+                None => {
+                    self.setvar(var, x);
+                    self.eval_derivative(expr, var)?
+                }
+            };
+            if y == 0.0 {
+                return Ok(x);
+            } else if y.is_nan() || dy.is_nan() {
+                return Err(SolveError::Eval(format!("Not a number at {} = {}", var, x)));
+            } else if dy == 0.0 {
+                return Err(SolveError::ZeroDerivative { x });
+            }
+            let step = y / dy;
+            x -= step;
+            if !x.is_finite() {
+                break;
+            } else if step.abs() <= TOLERANCE + 4.0 * f64::EPSILON * x.abs() {
+                return Ok(x);
+            }
+        }
+        Err(SolveError::NoConvergence {
+            iterations: MAX_NEWTON_ITERATIONS,
+            x,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::parser::ShuntingParser;
+
+    const ROOT: f64 = 2.0945514815423265;
+
+    fn equation(eq: &str) -> RPNExpr {
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            ..Grammar::default()
+        };
+        ShuntingParser::parse_equation_with(eq, &grammar).unwrap()
+    }
+
+    #[test]
+    fn methods() {
+        let cx = MathContext::new();
+        let expr = equation("x^3 - 2x = 5");
+        assert_eq!(expr.to_string(), "x ^ 3 - 2 * x - 5");
+        let close = |x: Result<f64, SolveError>| (x.unwrap() - ROOT).abs() < 1e-10;
+        assert!(close(cx.solve(&expr, "x", (2.0, 3.0))));
+        assert!(close(cx.solve(&expr, "x", 2.0)));
+        assert!(close(cx.solve_with(
+            &expr,
+            "x",
+            (0.0, 10.0),
+            Method::Bisection
+        )));
+        let symbolic = Method::Newton(Derivative::Symbolic);
+        assert!(close(cx.solve_with(&expr, "x", 3.0, symbolic)));
+        assert!(close(cx.solve_with(&expr, "x", (1.0, 3.0), symbolic)));
+
+        // user functions and variables from the context
+        let mut cx = MathContext::new();
+        cx.define("f(t) = t^3 - 2 * t").unwrap();
+        cx.setvar("c", 5.0);
+        let expr = equation("f(x) = c");
+        assert!(close(cx.solve(&expr, "x", 2.0)));
+        assert!(close(cx.solve(&expr, "x", (2.0, 3.0))));
+        // the context is left alone
+        assert_eq!(cx.vars.get("x"), None);
+        let sqrt2 = cx.solve(&equation("y^2 = 2"), "y", 1.0).unwrap();
+        assert!((sqrt2 - 2f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn failures() {
+        let cx = MathContext::new();
+        let expr = equation("x^2 + 1");
+        assert_eq!(
+            cx.solve(&expr, "x", (-1.0, 1.0)),
+            Err(SolveError::NoSignChange { a: -1.0, b: 1.0 })
+        );
+        assert_eq!(
+            cx.solve(&expr, "x", 0.0),
+            Err(SolveError::ZeroDerivative { x: 0.0 })
+        );
+        assert!(matches!(
+            cx.solve(&expr, "x", 3.0),
+            Err(SolveError::NoConvergence {
+                iterations: 100,
+                ..
+            })
+        ));
+        assert_eq!(
+            cx.solve_with(&expr, "x", 1.0, Method::Brent),
+            Err(SolveError::NeedsBracket)
+        );
+        assert_eq!(
+            cx.solve(&equation("x + y = 1"), "x", 0.0),
+            Err(SolveError::Eval("Unknown Variable: y".to_string()))
+        );
+        assert_eq!(
+            cx.solve(&equation("x^0.5 = 1"), "x", (-4.0, 4.0)),
+            Err(SolveError::Eval("Not a number at x = -4".to_string()))
+        );
+        assert_eq!(
+            SolveError::NoSignChange { a: -1.0, b: 1.0 }.to_string(),
+            "No sign change between -1 and 1"
+        );
+        assert_eq!(
+            ShuntingParser::parse_equation("x = 1 = 2"),
+            Err("More than one '=' in equation".to_string())
+        );
+        assert_eq!(
+            ShuntingParser::parse_equation("x ="),
+            Err("Empty side in equation".to_string())
+        );
+    }
+}
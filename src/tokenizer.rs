@@ -1,57 +1,304 @@
+use crate::grammar::{Fixity, Grammar};
 use crate::scanner::Scanner;
+use crate::units::Unit;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ops::Range;
+
+// The base a number was written in, kept to print it back the same way
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Radix {
+    Bin,
+    Oct,
+    Dec,
+    Hex,
+}
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum MathToken {
     Unknown(String),
-    Number(f64),
+    Number(f64, Radix),
+    Integer(u64, Radix), // only with Grammar::integer
     Variable(String),
     Function(String, usize), // arity
-    UOp(String),
+    UOp(String, Fixity),     // prefix or postfix
     BOp(String),
     OParen,
     CParen,
     Comma,
+    Quote(usize), // the next n tokens are an argument evaluated on demand
+    Unit(Unit),   // one of a unit like 'm/s', only with Grammar::units
+}
+
+impl MathToken {
+    // how many operands this token takes from the RPN stack
+    pub fn arity(&self) -> usize {
+        match *self {
+            MathToken::Function(_, arity) => arity,
+            MathToken::BOp(_) => 2,
+            MathToken::UOp(..) => 1,
+            _ => 0,
+        }
+    }
+}
+
+// read an integer like 42 or 0xFF exactly, if it fits
+fn parse_integer(num: &str) -> Option<(u64, Radix)> {
+    let (radix, base, digits) = match num.get(..2) {
+        Some("0b") => (Radix::Bin, 2, &num[2..]),
+        Some("0o") => (Radix::Oct, 8, &num[2..]),
+        Some("0x") => (Radix::Hex, 16, &num[2..]),
+        _ => (Radix::Dec, 10, num),
+    };
+    u64::from_str_radix(digits, base).ok().map(|n| (n, radix))
+}
+
+// read a number as matched by Scanner::scan_number
+fn parse_number(num: &str) -> (f64, Radix) {
+    use std::str::FromStr;
+    let (sign, unsigned) = match num.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, num.trim_start_matches('+')),
+    };
+    let (radix, base, digits) = match unsigned.get(..2) {
+        Some("0b") => (Radix::Bin, 2, &unsigned[2..]),
+        Some("0o") => (Radix::Oct, 8, &unsigned[2..]),
+        Some("0x") => (Radix::Hex, 16, &unsigned[2..]),
+        _ => return (f64::from_str(num).unwrap(), Radix::Dec),
+    };
+    // only hex floats have fractional and (binary) exponent parts, exponents
+    // too long for an i32 saturate, the value becomes inf or 0 either way
+    let (mantissa, exponent) = match digits.find(['p', 'P']) {
+        Some(p) => {
+            let exponent = &digits[p + 1..];
+            let saturated = if exponent.starts_with('-') {
+                i32::MIN
+            } else {
+                i32::MAX
+            };
+            (&digits[..p], i32::from_str(exponent).unwrap_or(saturated))
+        }
+        None => (digits, 0),
+    };
+    let mut value = 0.0;
+    let mut scale = 1.0;
+    let mut fraction = false;
+    for c in mantissa.chars() {
+        match c.to_digit(base) {
+            Some(d) if fraction => {
+                scale /= base as f64;
+                value += d as f64 * scale;
+            }
+            Some(d) => value = value * base as f64 + d as f64,
+            None => fraction = true, // the '.'
+        }
+    }
+    // scale in two steps so results near the ends of the range don't
+    // under or overflow on the way, and 0 doesn't become 0 * inf
+    let exponent = exponent.clamp(-4000, 4000);
+    let half = exponent / 2;
+    if value != 0.0 {
+        value = value * 2f64.powi(half) * 2f64.powi(exponent - half);
+    }
+    (sign * value, radix)
+}
+
+// does the token finish an operand, eg: '3', 'x' or ')'
+fn ends_operand(token: &Option<MathToken>) -> bool {
+    matches!(
+        *token,
+        Some(MathToken::Number(..))
+            | Some(MathToken::Integer(..))
+            | Some(MathToken::Variable(_))
+            | Some(MathToken::Unit(_))
+            | Some(MathToken::CParen)
+            | Some(MathToken::UOp(_, Fixity::Postfix))
+    )
+}
+
+// two operands next to each other without an operator, eg: '2 x'
+pub fn is_juxtaposition(prev: &Option<MathToken>, next: &MathToken) -> bool {
+    let starts_operand = matches!(
+        *next,
+        MathToken::Number(..)
+            | MathToken::Integer(..)
+            | MathToken::Variable(_)
+            | MathToken::Function(_, _)
+            | MathToken::OParen
+    );
+    ends_operand(prev) && starts_operand
 }
 
 pub struct MathTokenizer<I: Iterator<Item = char>> {
     src: Scanner<I>,
     prev: Option<MathToken>,
+    pending: VecDeque<(MathToken, Range<usize>)>,
+    span: Range<usize>, // where the last token came from in the source
+    grammar: Grammar,
+    depth: usize,     // how many parens are open
+    bars: Vec<usize>, // paren depth inside each open '|'
 }
 
 impl<I: Iterator<Item = char>> MathTokenizer<I> {
     pub fn new(source: I) -> Self {
+        Self::with_grammar(source, Grammar::default())
+    }
+
+    pub fn with_grammar(source: I, grammar: Grammar) -> Self {
         MathTokenizer {
             src: Scanner::new(source),
             prev: None,
+            pending: VecDeque::new(),
+            span: 0..0,
+            grammar,
+            depth: 0,
+            bars: Vec::new(),
         }
     }
 
     // when would a minus be unary? we need to know the prev token
     fn makes_unary(prev: &Option<MathToken>) -> bool {
-        !matches!(*prev, Some(MathToken::Number(_)) | Some(MathToken::Variable(_)) | Some(MathToken::CParen))
+        !ends_operand(prev)
+    }
+
+    // bars like in '|x - 3|' are read as 'abs(x - 3)', like with minus a bar
+    // opens when an operand is expected, else it closes the innermost bar
+    // if no parens were left open since then
+    fn abs_bar(&mut self) -> MathToken {
+        if !Self::makes_unary(&self.prev) && self.bars.last() == Some(&self.depth) {
+            self.bars.pop();
+            MathToken::CParen
+        } else if Self::makes_unary(&self.prev) || self.grammar.implicit_multiplication {
+            self.bars.push(self.depth + 1);
+            let at = self.src.extracted();
+            self.pending.push_back((MathToken::OParen, at..at));
+            MathToken::Function("abs".to_string(), 0)
+        } else {
+            MathToken::Unknown("|".to_string())
+        }
+    }
+
+    // pick the operator for a symbol, a prefix one where an operand is
+    // expected, else an infix or postfix one, falling back to whatever the
+    // symbol is defined as
+    fn operator(&self, symbol: String) -> Option<MathToken> {
+        let order = if Self::makes_unary(&self.prev) {
+            [Fixity::Prefix, Fixity::Infix, Fixity::Postfix]
+        } else {
+            [Fixity::Infix, Fixity::Postfix, Fixity::Prefix]
+        };
+        let op = order
+            .iter()
+            .find_map(|&fixity| self.grammar.operators.get(&symbol, fixity))?;
+        match op.fixity {
+            Fixity::Infix => Some(MathToken::BOp(symbol)),
+            fixity => Some(MathToken::UOp(symbol, fixity)),
+        }
+    }
+
+    // the char offsets of the last token in the source, tokens that were
+    // not written out like an implicit '*' get an empty span
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
     }
 
-    fn get_token(&mut self) -> Option<MathToken> {
+    fn get_token(&mut self) -> Option<(MathToken, Range<usize>)> {
+        if let Some(pending) = self.pending.pop_front() {
+            return Some(pending);
+        }
         self.src.scan_whitespace(); // discard whatever came before + and spaces
-        if let Some(op) = self.src.scan_math_op() {
+        let start = self.src.extracted();
+        let token = self.scan_token()?;
+        let span = start..self.src.extracted();
+        let (token, span) = self.scan_unit(token, span);
+        if self.grammar.implicit_multiplication && is_juxtaposition(&self.prev, &token) {
+            self.pending.push_front((token, span));
+            return Some((MathToken::BOp("*".to_string()), start..start));
+        }
+        Some((token, span))
+    }
+
+    // with units, a number followed by a unit reads as '(number * unit)' so
+    // that '3 m / 2 s' divides by '2 s', a unit also follows 'in'
+    fn scan_unit(&mut self, token: MathToken, span: Range<usize>) -> (MathToken, Range<usize>) {
+        let units = match self.grammar.units {
+            Some(ref units) => units,
+            None => return (token, span),
+        };
+        let quantity = match token {
+            MathToken::Number(..) | MathToken::Integer(..) => true,
+            MathToken::BOp(ref op) if op == "in" => false,
+            _ => return (token, span),
+        };
+        self.src.scan_whitespace();
+        let start = self.src.extracted();
+        let unit = match self.src.scan_unit(|name| units.get(name).is_some()) {
+            Some(name) => match units.parse(&name) {
+                Ok(unit) => MathToken::Unit(unit),
+                // eg: powers too large like 'm^999'
+                Err(_) => MathToken::Unknown(name),
+            },
+            None => return (token, span),
+        };
+        let (end, unit_span) = (self.src.extracted(), start..self.src.extracted());
+        if !quantity {
+            self.pending.push_back((unit, unit_span));
+            return (token, span);
+        }
+        let times = MathToken::BOp("*".to_string());
+        self.pending.push_back((token, span.clone()));
+        self.pending.push_back((times, span.end..span.end));
+        self.pending.push_back((unit, unit_span));
+        self.pending.push_back((MathToken::CParen, end..end));
+        (MathToken::OParen, span.start..span.start)
+    }
+
+    fn scan_token(&mut self) -> Option<MathToken> {
+        let symbols: Vec<&str> = self
+            .grammar
+            .operators
+            .iter()
+            .filter(|op| !op.is_word())
+            .map(|op| op.symbol.as_ref())
+            .collect();
+        if let Some(op) = self.src.scan_math_op(&symbols) {
             return match op.as_ref() {
                 "(" => Some(MathToken::OParen),
                 ")" => Some(MathToken::CParen),
                 "," => Some(MathToken::Comma),
-                "!" => Some(MathToken::UOp(op)),
-                "-" if Self::makes_unary(&self.prev) => Some(MathToken::UOp(op)),
-                _ => Some(MathToken::BOp(op)),
+                "=" => Some(MathToken::BOp(op)), // only in definitions
+                _ => match self.operator(op.clone()) {
+                    Some(token) => Some(token),
+                    None if op == "|" => Some(self.abs_bar()),
+                    None => Some(MathToken::Unknown(op)),
+                },
             };
         }
         if let Some(id) = self.src.scan_identifier() {
+            if let Some(token) = self.operator(id.clone()) {
+                return Some(token);
+            }
             return match self.src.peek() {
                 Some('(') => Some(MathToken::Function(id, 0)),
                 _ => Some(MathToken::Variable(id)),
             };
         }
         if let Some(num) = self.src.scan_number() {
-            use std::str::FromStr;
-            return Some(MathToken::Number(f64::from_str(&num).unwrap()));
+            let num = num.replace('_', "");
+            let suffixes: Vec<_> = self.grammar.suffixes.iter().map(|s| s.0).collect();
+            let scale = match self.src.scan_suffix(&suffixes) {
+                Some(suffix) => self.grammar.suffixes.iter().find(|s| s.0 == suffix),
+                None if self.grammar.integer => match parse_integer(&num) {
+                    Some((n, radix)) => return Some(MathToken::Integer(n, radix)),
+                    None => None,
+                },
+                None => None,
+            };
+            let (value, radix) = parse_number(&num);
+            return Some(MathToken::Number(value * scale.map_or(1.0, |s| s.1), radix));
         }
         if self.src.next().is_some() {
             return Some(MathToken::Unknown(self.src.extract_string()));
@@ -63,7 +310,13 @@ impl<I: Iterator<Item = char>> MathTokenizer<I> {
 impl<I: Iterator<Item = char>> Iterator for MathTokenizer<I> {
     type Item = MathToken;
     fn next(&mut self) -> Option<Self::Item> {
-        let token = self.get_token();
+        let (token, span) = self.get_token().unzip();
+        self.span = span.unwrap_or(self.span.end..self.span.end);
+        match token {
+            Some(MathToken::OParen) => self.depth += 1,
+            Some(MathToken::CParen) => self.depth = self.depth.saturating_sub(1),
+            _ => (),
+        }
         self.prev = token.clone();
         token
     }
@@ -72,29 +325,31 @@ impl<I: Iterator<Item = char>> Iterator for MathTokenizer<I> {
 ///////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
+#[allow(clippy::useless_format)]
 mod tests {
-    use super::{MathToken::*, MathTokenizer};
+    use super::{MathToken::*, MathTokenizer, Radix::*};
+    use crate::grammar::Fixity::*;
 
     #[test]
     fn basic_ops() {
         let mut lx = MathTokenizer::new("3+4*2/-(1-5)^2^3".chars());
         let expect = [
-            Number(3.0),
+            Number(3.0, Dec),
             BOp(format!("+")),
-            Number(4.0),
+            Number(4.0, Dec),
             BOp(format!("*")),
-            Number(2.0),
+            Number(2.0, Dec),
             BOp(format!("/")),
-            UOp(format!("-")),
+            UOp(format!("-"), Prefix),
             OParen,
-            Number(1.0),
+            Number(1.0, Dec),
             BOp(format!("-")),
-            Number(5.0),
+            Number(5.0, Dec),
             CParen,
             BOp(format!("^")),
-            Number(2.0),
+            Number(2.0, Dec),
             BOp(format!("^")),
-            Number(3.0),
+            Number(3.0, Dec),
         ];
         for exp_token in expect.iter() {
             let token = lx.next().unwrap();
@@ -107,7 +362,7 @@ mod tests {
     fn mixed_ops() {
         let mut lx = MathTokenizer::new("3.4e-2 * sin(x)/(7! % -4) * max(2, x)".chars());
         let expect = [
-            Number(3.4e-2),
+            Number(3.4e-2, Dec),
             BOp(format!("*")),
             Function(format!("sin"), 0),
             OParen,
@@ -115,16 +370,16 @@ mod tests {
             CParen,
             BOp(format!("/")),
             OParen,
-            Number(7.0),
-            UOp(format!("!")),
+            Number(7.0, Dec),
+            UOp(format!("!"), Postfix),
             BOp(format!("%")),
-            UOp(format!("-")),
-            Number(4.0),
+            UOp(format!("-"), Prefix),
+            Number(4.0, Dec),
             CParen,
             BOp(format!("*")),
             Function(format!("max"), 0),
             OParen,
-            Number(2.0),
+            Number(2.0, Dec),
             Comma,
             Variable(format!("x")),
             CParen,
@@ -142,8 +397,8 @@ mod tests {
         let expect = [
             Variable(format!("x")),
             BOp(format!("-")),
-            UOp(format!("-")),
-            UOp(format!("-")),
+            UOp(format!("-"), Prefix),
+            UOp(format!("-"), Prefix),
             Variable(format!("y")),
         ];
         for exp_token in expect.iter() {
@@ -152,4 +407,288 @@ mod tests {
         }
         assert_eq!(lx.next(), None);
     }
+
+    #[test]
+    fn implicit_multiplication() {
+        use crate::grammar::Grammar;
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            ..Grammar::default()
+        };
+        let mut lx =
+            MathTokenizer::with_grammar("2x + 3(x+1) - (a)(b) * 2e3 2e y".chars(), grammar);
+        let expect = [
+            Number(2.0, Dec),
+            BOp("*".to_string()),
+            Variable("x".to_string()),
+            BOp("+".to_string()),
+            Number(3.0, Dec),
+            BOp("*".to_string()),
+            OParen,
+            Variable("x".to_string()),
+            BOp("+".to_string()),
+            Number(1.0, Dec),
+            CParen,
+            BOp("-".to_string()),
+            OParen,
+            Variable("a".to_string()),
+            CParen,
+            BOp("*".to_string()),
+            OParen,
+            Variable("b".to_string()),
+            CParen,
+            BOp("*".to_string()),
+            Number(2e3, Dec),
+            BOp("*".to_string()),
+            Number(2.0, Dec),
+            BOp("*".to_string()),
+            Variable("e".to_string()),
+            BOp("*".to_string()),
+            Variable("y".to_string()),
+        ];
+        for exp_token in expect.iter() {
+            let token = lx.next().unwrap();
+            assert_eq!(*exp_token, token);
+        }
+        assert_eq!(lx.next(), None);
+    }
+
+    #[test]
+    fn abs_bars() {
+        let mut lx = MathTokenizer::new("||x|-1|*|y|".chars());
+        let expect = [
+            Function("abs".to_string(), 0),
+            OParen,
+            Function("abs".to_string(), 0),
+            OParen,
+            Variable("x".to_string()),
+            CParen,
+            BOp("-".to_string()),
+            Number(1.0, Dec),
+            CParen,
+            BOp("*".to_string()),
+            Function("abs".to_string(), 0),
+            OParen,
+            Variable("y".to_string()),
+            CParen,
+        ];
+        for exp_token in expect.iter() {
+            let token = lx.next().unwrap();
+            assert_eq!(*exp_token, token);
+        }
+        assert_eq!(lx.next(), None);
+
+        // a bar can't close over an unbalanced paren
+        let tokens: Vec<_> = MathTokenizer::new("|(x|)".chars()).collect();
+        assert_eq!(tokens[4], Unknown("|".to_string()));
+    }
+
+    #[test]
+    fn radix_numbers() {
+        let lx =
+            MathTokenizer::new("0x1F + 0b1011 * 0o17 - 0x1.8p3 / 0x.8 + 0xAp-1 0x 0b2".chars());
+        let expect = vec![
+            Number(31.0, Hex),
+            BOp("+".to_string()),
+            Number(11.0, Bin),
+            BOp("*".to_string()),
+            Number(15.0, Oct),
+            BOp("-".to_string()),
+            Number(12.0, Hex),
+            BOp("/".to_string()),
+            Number(0.0, Dec),
+            Variable("x".to_string()),
+            Unknown(".".to_string()),
+            Number(8.0, Dec),
+            BOp("+".to_string()),
+            Number(5.0, Hex),
+            Number(0.0, Dec),
+            Variable("x".to_string()),
+            Number(0.0, Dec),
+            Variable("b2".to_string()),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+
+        // exponents out of range saturate
+        let lx = MathTokenizer::new("0x1p99999999999 0x1p-99999999999 0x0p99999 0x1p-1074".chars());
+        let expect = vec![
+            Number(f64::INFINITY, Hex),
+            Number(0.0, Hex),
+            Number(0.0, Hex),
+            Number(f64::from_bits(1), Hex),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+    }
+
+    #[test]
+    fn digit_separators() {
+        let lx = MathTokenizer::new("1_000_000 + 0xFFFF_0000 - 1_0.2_5e1_0 * 1__0 2_".chars());
+        let expect = vec![
+            Number(1e6, Dec),
+            BOp("+".to_string()),
+            Number(4294901760.0, Hex),
+            BOp("-".to_string()),
+            Number(10.25e10, Dec),
+            BOp("*".to_string()),
+            Number(1.0, Dec),
+            Variable("__0".to_string()),
+            Number(2.0, Dec),
+            Variable("_".to_string()),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+    }
+
+    #[test]
+    fn suffixes() {
+        use crate::grammar::Grammar;
+        let grammar = Grammar {
+            implicit_multiplication: true,
+            suffixes: Grammar::si_suffixes(),
+            ..Grammar::default()
+        };
+        let lx =
+            MathTokenizer::with_grammar("4.7k + 10M - 3u / 220n * 2km + 2E3 2m".chars(), grammar);
+        let expect = vec![
+            Number(4.7e3, Dec),
+            BOp("+".to_string()),
+            Number(10e6, Dec),
+            BOp("-".to_string()),
+            Number(3e-6, Dec),
+            BOp("/".to_string()),
+            Number(220e-9, Dec),
+            BOp("*".to_string()),
+            Number(2.0, Dec),
+            BOp("*".to_string()),
+            Variable("km".to_string()),
+            BOp("+".to_string()),
+            Number(2e3, Dec),
+            BOp("*".to_string()),
+            Number(2e-3, Dec),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+    }
+
+    #[test]
+    fn integer_ops() {
+        use crate::grammar::Grammar;
+        let src = "~0xFFFF_FFFF_FFFF_FFFF | 1 << 2 xor 3 & 4 >> 1.5 < 6 <= 7";
+        let lx = MathTokenizer::with_grammar(src.chars(), Grammar::integer());
+        let expect = vec![
+            UOp("~".to_string(), Prefix),
+            Integer(u64::MAX, Hex),
+            BOp("|".to_string()),
+            Integer(1, Dec),
+            BOp("<<".to_string()),
+            Integer(2, Dec),
+            BOp("xor".to_string()),
+            Integer(3, Dec),
+            BOp("&".to_string()),
+            Integer(4, Dec),
+            BOp(">>".to_string()),
+            Number(1.5, Dec),
+            BOp("<".to_string()),
+            Integer(6, Dec),
+            BOp("<=".to_string()),
+            Integer(7, Dec),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+    }
+
+    #[test]
+    fn custom_operators() {
+        use crate::grammar::{Assoc, Grammar, Operator};
+        let mut grammar = Grammar::default();
+        grammar
+            .operators
+            .add(Operator::infix("//", 30, Assoc::Left));
+        grammar
+            .operators
+            .add(Operator::infix("mod", 30, Assoc::Left));
+        grammar.operators.add(Operator::infix("⊕", 25, Assoc::Left));
+        let lx = MathTokenizer::with_grammar("7//2 mod modulus⊕-1".chars(), grammar);
+        let expect = [
+            Number(7.0, Dec),
+            BOp("//".to_string()),
+            Number(2.0, Dec),
+            BOp("mod".to_string()),
+            Variable("modulus".to_string()),
+            BOp("⊕".to_string()),
+            UOp("-".to_string(), Prefix),
+            Number(1.0, Dec),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+
+        // operators missing from the table are unknown
+        let lx = MathTokenizer::new("a & b".chars());
+        assert_eq!(lx.collect::<Vec<_>>()[1], Unknown("&".to_string()));
+    }
+
+    #[test]
+    fn postfix_ops() {
+        // a postfix operator ends an operand, so the '-' after it is binary
+        let lx = MathTokenizer::new("3! - 2".chars());
+        let expect = [
+            Number(3.0, Dec),
+            UOp("!".to_string(), Postfix),
+            BOp("-".to_string()),
+            Number(2.0, Dec),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+    }
+
+    #[test]
+    fn units() {
+        use crate::grammar::Grammar;
+        let grammar = Grammar::units();
+        let units = grammar.units.clone().unwrap();
+        let unit = |name: &str| Unit(units.parse(name).unwrap());
+        let mut lx =
+            MathTokenizer::with_grammar("9.81 m/s^2 / 2 s in km/h".chars(), grammar.clone());
+        let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+        while let Some(token) = lx.next() {
+            tokens.push(token);
+            spans.push(lx.span());
+        }
+        let expect = [
+            OParen,
+            Number(9.81, Dec),
+            BOp("*".to_string()),
+            unit("m/s^2"),
+            CParen,
+            BOp("/".to_string()),
+            OParen,
+            Number(2.0, Dec),
+            BOp("*".to_string()),
+            unit("s"),
+            CParen,
+            BOp("in".to_string()),
+            unit("km/h"),
+        ];
+        assert_eq!(tokens, expect);
+        assert_eq!(spans[..5], [0..0, 0..4, 4..4, 5..10, 10..10]);
+        assert_eq!(spans[12], 20..24);
+
+        // units only follow numbers, a name that isn't a unit is left alone
+        let lx = MathTokenizer::with_grammar("m * 2 kg x".chars(), grammar.clone());
+        let expect = [
+            Variable("m".to_string()),
+            BOp("*".to_string()),
+            OParen,
+            Number(2.0, Dec),
+            BOp("*".to_string()),
+            unit("kg"),
+            CParen,
+            Variable("x".to_string()),
+        ];
+        assert_eq!(lx.collect::<Vec<_>>(), expect);
+        // nor is a function, and a power only belongs to the unit right after it
+        let lx = MathTokenizer::with_grammar("2 min(1) + 3 m ^2".chars(), grammar);
+        let tokens: Vec<_> = lx.collect();
+        assert_eq!(tokens[1], Function("min".to_string(), 0));
+        assert_eq!(
+            tokens[9..],
+            [unit("m"), CParen, BOp("^".to_string()), Number(2.0, Dec)]
+        );
+    }
 }
@@ -0,0 +1,618 @@
+use crate::parser::{binds_variable, is_lazy, RPNExpr};
+use crate::rpneval::{index_variable, series_indices, MathContext};
+use crate::tokenizer::{MathToken, Radix};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+// Quantities with units like '3 m / 2 s', see Grammar::units for reading
+// them and MathContext::eval_quantity for checking their dimensions
+
+// the SI base units, a dimension is the power of each of them
+const BASE: [&str; 7] = ["kg", "m", "s", "A", "K", "mol", "cd"];
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Dimension(pub [i8; 7]);
+
+impl Dimension {
+    pub fn is_dimensionless(&self) -> bool {
+        self.0.iter().all(|&power| power == 0)
+    }
+
+    // self * other^power, None if a power doesn't fit in an i8
+    fn combine(self, other: Dimension, power: i8) -> Option<Dimension> {
+        let mut dims = self;
+        for (own, other) in dims.0.iter_mut().zip(other.0.iter()) {
+            *own = own.checked_add(power.checked_mul(*other)?)?;
+        }
+        Some(dims)
+    }
+
+    // None unless every power stays whole, eg: (m^2)^0.5 is fine, m^0.5 not
+    fn powf(self, exp: f64) -> Option<Dimension> {
+        let mut dims = self;
+        for power in dims.0.iter_mut() {
+            let raised = f64::from(*power) * exp;
+            if raised.fract() != 0.0 || raised.abs() > f64::from(i8::MAX) {
+                return None;
+            }
+            *power = raised as i8;
+        }
+        Some(dims)
+    }
+}
+
+// like 'kg m/s^2', or 's^-1' when there is nothing to divide
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let factor = |name: &str, power: i8| match power {
+            1 => name.to_string(),
+            _ => format!("{}^{}", name, power),
+        };
+        let powers = BASE.iter().zip(self.0.iter());
+        let (num, den): (Vec<_>, Vec<_>) = powers.filter(|p| *p.1 != 0).partition(|p| *p.1 > 0);
+        let num: Vec<_> = num.into_iter().map(|(name, &p)| factor(name, p)).collect();
+        let text = match num.is_empty() {
+            true if den.is_empty() => "1".to_string(),
+            true => {
+                let den: Vec<_> = den.into_iter().map(|(name, &p)| factor(name, p)).collect();
+                den.join(" ")
+            }
+            false => {
+                let den = den
+                    .into_iter()
+                    .map(|(name, &p)| format!("/{}", factor(name, -p)));
+                num.join(" ") + &den.collect::<String>()
+            }
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// A unit as written, eg: 'km/h', with its size in SI units
+#[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Unit {
+    pub name: String,
+    pub scale: f64,
+    pub dims: Dimension,
+}
+
+// The units the tokenizer knows by name
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Units(HashMap<String, Unit>);
+
+impl Units {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the SI base and derived units and some common multiples of them
+    pub fn si() -> Self {
+        let mut units = Units::new();
+        for (i, name) in BASE.iter().enumerate() {
+            let mut dims = Dimension::default();
+            dims.0[i] = 1;
+            let unit = Unit {
+                name: name.to_string(),
+                scale: 1.0,
+                dims,
+            };
+            units.0.insert(name.to_string(), unit);
+        }
+        let derived = [
+            ("g", 1e-3, "kg"),
+            ("mg", 1e-6, "kg"),
+            ("km", 1e3, "m"),
+            ("cm", 1e-2, "m"),
+            ("mm", 1e-3, "m"),
+            ("µm", 1e-6, "m"),
+            ("nm", 1e-9, "m"),
+            ("ms", 1e-3, "s"),
+            ("µs", 1e-6, "s"),
+            ("ns", 1e-9, "s"),
+            ("min", 60.0, "s"),
+            ("h", 3600.0, "s"),
+            ("mA", 1e-3, "A"),
+            ("L", 1e-3, "m^3"),
+            ("mL", 1e-6, "m^3"),
+            ("Hz", 1.0, "s^-1"),
+            ("kHz", 1e3, "Hz"),
+            ("MHz", 1e6, "Hz"),
+            ("GHz", 1e9, "Hz"),
+            ("N", 1.0, "kg m/s^2"),
+            ("kN", 1e3, "N"),
+            ("Pa", 1.0, "N/m^2"),
+            ("kPa", 1e3, "Pa"),
+            ("bar", 1e5, "Pa"),
+            ("J", 1.0, "N m"),
+            ("kJ", 1e3, "J"),
+            ("W", 1.0, "J/s"),
+            ("kW", 1e3, "W"),
+            ("Wh", 3600.0, "J"),
+            ("kWh", 3.6e6, "J"),
+            ("C", 1.0, "A s"),
+            ("V", 1.0, "W/A"),
+            ("mV", 1e-3, "V"),
+            ("kV", 1e3, "V"),
+            ("Ω", 1.0, "V/A"),
+        ];
+        for (name, scale, unit) in derived.iter() {
+            units.define(name, *scale, unit).unwrap();
+        }
+        units
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Unit> {
+        self.0.get(name)
+    }
+
+    // add a unit as a multiple of others, eg: define("mph", 1609.344, "m/h")
+    pub fn define(&mut self, name: &str, scale: f64, unit: &str) -> Result<(), String> {
+        let unit = self.parse(unit)?;
+        let unit = Unit {
+            name: name.to_string(),
+            scale: scale * unit.scale,
+            dims: unit.dims,
+        };
+        self.0.insert(name.to_string(), unit);
+        Ok(())
+    }
+
+    // read units like 'kg m/s^2', factors are separated by spaces (or '*')
+    // and a '/' divides by the factor right after it
+    pub fn parse(&self, text: &str) -> Result<Unit, String> {
+        // each factor along with the sign of its power
+        let mut factors = Vec::new();
+        let (mut start, mut sign) = (0, 1);
+        for (i, c) in text.char_indices() {
+            if c == ' ' || c == '*' || c == '/' {
+                factors.push((sign, &text[start..i]));
+                sign = if c == '/' { -1 } else { 1 };
+                start = i + 1;
+            }
+        }
+        factors.push((sign, &text[start..]));
+
+        let mut scale = 1.0;
+        let mut dims = Dimension::default();
+        for (sign, factor) in factors {
+            let (name, power) = match factor.find('^') {
+                Some(at) => match factor[at + 1..].parse::<i8>().map(|p| p.checked_mul(sign)) {
+                    Ok(Some(power)) => (&factor[..at], power),
+                    _ => return Err(format!("Bad unit: {}", text)),
+                },
+                None => (factor, sign),
+            };
+            if name.is_empty() {
+                return Err(format!("Bad unit: {}", text));
+            }
+            let unit = self
+                .get(name)
+                .ok_or_else(|| format!("Unknown unit: {}", name))?;
+            scale *= unit.scale.powi(i32::from(power));
+            dims = dims
+                .combine(unit.dims, power)
+                .ok_or_else(|| format!("Unit power out of range: {}", text))?;
+        }
+        Ok(Unit {
+            name: text.replace('*', " "),
+            scale,
+            dims,
+        })
+    }
+}
+
+// A value in SI units, 'unit' is what it was converted to with 'in'
+#[derive(PartialEq, Clone, Debug)]
+pub struct Quantity {
+    pub value: f64,
+    pub dims: Dimension,
+    pub unit: Option<Unit>,
+}
+
+impl Quantity {
+    fn number(value: f64) -> Self {
+        Quantity {
+            value,
+            dims: Dimension::default(),
+            unit: None,
+        }
+    }
+
+    fn with_dims(value: f64, dims: Dimension) -> Self {
+        Quantity {
+            value,
+            dims,
+            unit: None,
+        }
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unit {
+            Some(ref unit) => write!(f, "{} {}", self.value / unit.scale, unit.name),
+            None if self.dims.is_dimensionless() => write!(f, "{}", self.value),
+            None => write!(f, "{} {}", self.value, self.dims),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum UnitError {
+    // the operands of 'op' must have the same dimension, eg: '1 m + 1 s'
+    Mismatch {
+        op: String,
+        lhs: Dimension,
+        rhs: Dimension,
+    },
+    // the operands of 'op' must be dimensionless, eg: 'sin(1 m)'
+    NotDimensionless {
+        op: String,
+        dims: Dimension,
+    },
+    // any other evaluation error
+    Eval(String),
+}
+
+impl fmt::Display for UnitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnitError::Mismatch {
+                ref op,
+                ref lhs,
+                ref rhs,
+            } => write!(f, "Incompatible units for {}: {} and {}", op, lhs, rhs),
+            UnitError::NotDimensionless { ref op, ref dims } => {
+                write!(f, "{} needs dimensionless operands, not {}", op, dims)
+            }
+            UnitError::Eval(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<String> for UnitError {
+    fn from(error: String) -> Self {
+        UnitError::Eval(error)
+    }
+}
+
+fn same_dims(op: &str, lhs: &Quantity, rhs: &Quantity) -> Result<Dimension, UnitError> {
+    if lhs.dims != rhs.dims {
+        return Err(UnitError::Mismatch {
+            op: op.to_string(),
+            lhs: lhs.dims,
+            rhs: rhs.dims,
+        });
+    }
+    Ok(lhs.dims)
+}
+
+impl MathContext {
+    // evaluate checking that dimensions agree, eg: '1 m + 1 s' is an error,
+    // the result is in SI units unless converted with 'in'
+    pub fn eval_quantity(&self, rpn: &RPNExpr) -> Result<Quantity, UnitError> {
+        self.eval_quantities(&rpn.0)
+    }
+
+    fn eval_quantities(&self, rpn: &[MathToken]) -> Result<Quantity, UnitError> {
+        let missing = || UnitError::Eval("Wrong number of arguments".to_string());
+        let mut operands = Vec::new();
+        let mut quoted = Vec::new();
+
+        let mut tokens = rpn.iter();
+        while let Some(token) = tokens.next() {
+            let quantity = match *token {
+                MathToken::Number(x, _) => Quantity::number(x),
+                MathToken::Integer(n, _) => Quantity::number(n as f64),
+                MathToken::Variable(ref var) => match self.vars.get(var) {
+                    Some(value) => Quantity::number(*value),
+                    None => return Err(UnitError::Eval(format!("Unknown Variable: {}", var))),
+                },
+                MathToken::Unit(ref unit) => Quantity {
+                    value: unit.scale,
+                    dims: unit.dims,
+                    unit: Some(unit.clone()),
+                },
+                MathToken::Quote(n) => {
+                    let rest = tokens.as_slice();
+                    if n > rest.len() {
+                        return Err(missing());
+                    }
+                    quoted.push(&rest[..n]);
+                    tokens = rest[n..].iter();
+                    continue;
+                }
+                MathToken::Function(ref fname, arity) if is_lazy(fname) => {
+                    if arity > quoted.len() {
+                        return Err(missing());
+                    }
+                    let cut = quoted.len() - arity;
+                    let args = quoted.split_off(cut);
+                    if binds_variable(fname) && arity == 4 {
+                        self.eval_series_quantity(fname, &args)?
+                    } else if !binds_variable(fname) && arity % 2 == 1 {
+                        self.eval_piecewise_quantity(&args)?
+                    } else {
+                        return Err(missing());
+                    }
+                }
+                _ => {
+                    let arity = token.arity();
+                    if arity > operands.len() {
+                        return Err(missing());
+                    }
+                    let cut = operands.len() - arity;
+                    let args = operands.split_off(cut);
+                    self.apply_quantity(token, args)?
+                }
+            };
+            operands.push(quantity);
+        }
+        operands.pop().ok_or_else(missing)
+    }
+
+    fn eval_piecewise_quantity(&self, args: &[&[MathToken]]) -> Result<Quantity, UnitError> {
+        for case in args.chunks(2) {
+            match *case {
+                [cond, value] if self.eval_quantities(cond)?.value != 0.0 => {
+                    return self.eval_quantities(value)
+                }
+                [default] => return self.eval_quantities(default),
+                _ => (),
+            }
+        }
+        unreachable!()
+    }
+
+    // sum, prod and integrate over dimensionless bounds, the terms of a sum
+    // must agree and an integral has the dimension of its integrand
+    fn eval_series_quantity(
+        &self,
+        fname: &str,
+        args: &[&[MathToken]],
+    ) -> Result<Quantity, UnitError> {
+        let index = index_variable(fname, args[1])?;
+        let bound = |arg| -> Result<f64, UnitError> {
+            let bound = self.eval_quantities(arg)?;
+            if !bound.dims.is_dimensionless() {
+                let op = fname.to_string();
+                return Err(UnitError::NotDimensionless {
+                    op,
+                    dims: bound.dims,
+                });
+            }
+            Ok(bound.value)
+        };
+        let (from, to) = (bound(args[2])?, bound(args[3])?);
+        let mut cx = self.clone();
+        if fname == "integrate" {
+            cx.setvar(index, from);
+            let dims = cx.eval_quantities(args[0])?.dims;
+            let (value, _) = self.integrate(&RPNExpr(args[0].to_vec()), index, from, to)?;
+            return Ok(Quantity {
+                value,
+                dims,
+                unit: None,
+            });
+        }
+        let op = MathToken::BOp(if fname == "sum" { "+" } else { "*" }.to_string());
+        let mut total = None;
+        for i in series_indices(from, to)? {
+            cx.setvar(index, i);
+            let term = cx.eval_quantities(args[0])?;
+            total = Some(match total {
+                Some(total) => self.apply_quantity(&op, vec![total, term])?,
+                None => term,
+            });
+        }
+        let empty = if fname == "sum" { 0.0 } else { 1.0 };
+        Ok(total.unwrap_or_else(|| Quantity::number(empty)))
+    }
+
+    // operators and functions that know about dimensions, anything else
+    // takes dimensionless operands and is evaluated as usual
+    fn apply_quantity(
+        &self,
+        token: &MathToken,
+        args: Vec<Quantity>,
+    ) -> Result<Quantity, UnitError> {
+        if args.is_empty() {
+            let value = self.eval(&RPNExpr(vec![token.clone()]))?;
+            return Ok(Quantity::number(value));
+        }
+        let values: Vec<_> = args.iter().map(|arg| arg.value).collect();
+        let name = match *token {
+            MathToken::BOp(ref op) | MathToken::UOp(ref op, _) if self.ops.contains_key(op) => "",
+            MathToken::BOp(ref op) | MathToken::UOp(ref op, _) | MathToken::Function(ref op, _) => {
+                op
+            }
+            _ => return Err(UnitError::Eval(format!("Bad Token: {:?}", token))),
+        };
+        let (lhs, rhs) = (&args[0], args.last().unwrap());
+        let dims = match (name, args.len()) {
+            ("+", 2) | ("-", 2) | ("%", 2) => same_dims(name, lhs, rhs)?,
+            ("*", 2) | ("/", 2) => {
+                let power = if name == "*" { 1 } else { -1 };
+                match lhs.dims.combine(rhs.dims, power) {
+                    Some(dims) => dims,
+                    None => {
+                        let error = format!(
+                            "Unit power out of range: {} {} {}",
+                            lhs.dims, name, rhs.dims
+                        );
+                        return Err(UnitError::Eval(error));
+                    }
+                }
+            }
+            ("-", 1) | ("abs", 1) => lhs.dims,
+            ("max", _) | ("min", _) => {
+                for arg in args.iter() {
+                    same_dims(name, lhs, arg)?;
+                }
+                lhs.dims
+            }
+            ("^", 2) if rhs.dims.is_dimensionless() => match lhs.dims.powf(rhs.value) {
+                Some(dims) => dims,
+                None => {
+                    let op = format!("{} to a power of {}", name, rhs.value);
+                    return Err(UnitError::NotDimensionless { op, dims: lhs.dims });
+                }
+            },
+            ("<", 2) | ("<=", 2) | (">", 2) | (">=", 2) | ("==", 2) | ("!=", 2) => {
+                same_dims(name, lhs, rhs)?;
+                Dimension::default()
+            }
+            ("&&", 2) => Dimension::default(),
+            ("in", 2) => {
+                let dims = same_dims(name, lhs, rhs)?;
+                return match rhs.unit {
+                    Some(ref unit) if args.len() == 2 => Ok(Quantity {
+                        value: lhs.value,
+                        dims,
+                        unit: Some(unit.clone()),
+                    }),
+                    _ => Err(UnitError::Eval("Expected a unit after in".to_string())),
+                };
+            }
+            _ => {
+                if let Some(arg) = args.iter().find(|arg| !arg.dims.is_dimensionless()) {
+                    let op = match *token {
+                        MathToken::Function(ref fname, _) => fname.to_string(),
+                        MathToken::BOp(ref op) | MathToken::UOp(ref op, _) => op.to_string(),
+                        _ => unreachable!(),
+                    };
+                    return Err(UnitError::NotDimensionless { op, dims: arg.dims });
+                }
+                Dimension::default()
+            }
+        };
+        // the dimensions check out, the values evaluate as plain numbers
+        let mut rpn: Vec<_> = values
+            .into_iter()
+            .map(|x| MathToken::Number(x, Radix::Dec))
+            .collect();
+        rpn.push(token.clone());
+        Ok(Quantity::with_dims(self.eval(&RPNExpr(rpn))?, dims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::parser::ShuntingParser;
+
+    fn eval(expr: &str) -> Result<String, UnitError> {
+        let expr = ShuntingParser::parse_str_with(expr, &Grammar::units()).unwrap();
+        Ok(MathContext::new().eval_quantity(&expr)?.to_string())
+    }
+
+    #[test]
+    fn registry() {
+        let units = Units::si();
+        let newton = units.get("N").unwrap();
+        assert_eq!(newton.dims, Dimension([1, 1, -2, 0, 0, 0, 0]));
+        assert_eq!(newton.dims.to_string(), "kg m/s^2");
+        assert_eq!(units.get("Hz").unwrap().dims.to_string(), "s^-1");
+        let speed = units.parse("km/h").unwrap();
+        assert_eq!(
+            (speed.name.as_str(), speed.dims.to_string()),
+            ("km/h", "m/s".to_string())
+        );
+        assert!((speed.scale - 1.0 / 3.6).abs() < 1e-12);
+        assert_eq!(units.parse("kg*m/s/s").unwrap().dims, newton.dims);
+        assert_eq!(
+            Units::si().define("x", 1.0, "m^100 m^100"),
+            Err("Unit power out of range: m^100 m^100".to_string())
+        );
+        assert_eq!(
+            units.parse("m/s^-128"),
+            Err("Bad unit: m/s^-128".to_string())
+        );
+        assert_eq!(
+            units.parse("m/furlong"),
+            Err("Unknown unit: furlong".to_string())
+        );
+        assert_eq!(units.parse("m^x"), Err("Bad unit: m^x".to_string()));
+
+        let mut units = Units::new();
+        units.define("mph", 1.0, "m/h").unwrap_err();
+        let mut units = Units::si();
+        units.define("mi", 1609.344, "m").unwrap();
+        units.define("mph", 1.0, "mi/h").unwrap();
+        assert!((units.get("mph").unwrap().scale - 0.44704).abs() < 1e-12);
+    }
+
+    #[test]
+    fn quantities() {
+        assert_eq!(eval("3 m / 2 s"), Ok("1.5 m/s".to_string()));
+        assert_eq!(eval("5 kg * 9.8 m/s^2 in N"), Ok("49 N".to_string()));
+        assert_eq!(eval("2 km + 500 m in km"), Ok("2.5 km".to_string()));
+        assert_eq!(eval("36 km/h in m/s"), Ok("10 m/s".to_string()));
+        assert_eq!(eval("(3 m)^2 / 1.5 m^2"), Ok("6".to_string()));
+        assert_eq!(eval("max(1 km, 200 m) in m"), Ok("1000 m".to_string()));
+        assert_eq!(eval("if(2 s > 1 s, 1 m, 2 m)"), Ok("1 m".to_string()));
+        assert_eq!(eval("1 / 4 s"), Ok("0.25 s^-1".to_string()));
+        assert_eq!(eval("-sin(pi / 2) * 2 A"), Ok("-2 A".to_string()));
+        assert_eq!(eval("sum(i * 1 m, i, 1, 3)"), Ok("6 m".to_string()));
+        assert_eq!(eval("prod(2 m, i, 1, 2)"), Ok("4 m^2".to_string()));
+        assert_eq!(
+            eval("integrate(t * 1 N, t, 0, 2)"),
+            Ok("2 kg m/s^2".to_string())
+        );
+
+        // eval works in SI units without looking at dimensions
+        let grammar = Grammar::units();
+        let expr = ShuntingParser::parse_str_with("1 km + 1 s in min", &grammar).unwrap();
+        assert_eq!(MathContext::new().eval(&expr), Ok(1001.0 / 60.0));
+    }
+
+    #[test]
+    fn dimension_errors() {
+        let mismatch = |op: &str, lhs, rhs| UnitError::Mismatch {
+            op: op.to_string(),
+            lhs: Units::si().get(lhs).unwrap().dims,
+            rhs: Units::si().get(rhs).unwrap().dims,
+        };
+        assert_eq!(eval("1 m + 1 s"), Err(mismatch("+", "m", "s")));
+        assert_eq!(eval("1 m < 1 kg"), Err(mismatch("<", "m", "kg")));
+        assert_eq!(eval("10 m in s"), Err(mismatch("in", "m", "s")));
+        let error = eval("1 N - 1 J").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Incompatible units for -: kg m/s^2 and kg m^2/s^2"
+        );
+        let error = eval("sin(2 m)").unwrap_err();
+        assert_eq!(error.to_string(), "sin needs dimensionless operands, not m");
+        let error = eval("(2 m)^0.5").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "^ to a power of 0.5 needs dimensionless operands, not m"
+        );
+        assert_eq!(eval("2 m^2 ^ 0.5"), Ok("1.4142135623730951 m".to_string()));
+        assert_eq!(
+            eval("sum(if(i > 1, 1 m, 1 s), i, 1, 2)"),
+            Err(mismatch("+", "s", "m"))
+        );
+        let error = eval("sum(i, i, 1 m, 3 m)").unwrap_err();
+        assert_eq!(error.to_string(), "sum needs dimensionless operands, not m");
+        assert_eq!(
+            eval("1 m^100 * 1 m^100"),
+            Err(UnitError::Eval(
+                "Unit power out of range: m^100 * m^100".to_string()
+            ))
+        );
+        assert_eq!(
+            ShuntingParser::parse_str_with("1 m^999", &Grammar::units()),
+            Err("Bad token: m^999".to_string())
+        );
+        assert_eq!(
+            eval("2 m * y"),
+            Err(UnitError::Eval("Unknown Variable: y".to_string()))
+        );
+    }
+}